@@ -25,7 +25,7 @@ use std::collections::HashSet;
 
 use failure::Error;
 use spirit::{Empty, Spirit, SpiritInner};
-use spirit_tokio::TcpListen;
+use spirit_tokio::{TcpListen, Tracked};
 use tokio::net::TcpStream;
 use tokio::prelude::*;
 
@@ -66,10 +66,11 @@ msg = "Hello world"
 /// Handle one connection, the tokio way.
 fn handle_connection(
     spirit: &SpiritInner<Empty, Config>,
-    conn: TcpStream,
+    conn: Tracked<TcpStream>,
     _: &Empty,
 ) -> impl Future<Item = (), Error = Error> {
     let addr = conn
+        .get_ref()
         .peer_addr()
         .map(|addr| addr.to_string())
         .unwrap_or_else(|_| "<unknown>".to_owned());
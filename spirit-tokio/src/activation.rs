@@ -0,0 +1,245 @@
+//! Sockets passed to the application through systemd socket activation.
+//!
+//! The sockets are passed as file descriptors starting at 3, their count is in the `LISTEN_FDS`
+//! environment variable and their (optional) names in `LISTEN_FDNAMES`. See
+//! [`sd_listen_fds`](https://www.freedesktop.org/software/systemd/man/sd_listen_fds.html).
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::Result as IoResult;
+use std::net::{TcpListener, UdpSocket};
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixListener;
+use std::sync::Arc;
+
+use failure::Error;
+use listenfd::ListenFd;
+use parking_lot::Mutex;
+
+/// The first file descriptor passed by systemd, unless `LISTEN_FDS_FIRST_FD` says otherwise.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Which of the inherited sockets to use.
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Activated<'a> {
+    Fd(RawFd),
+    Name(&'a str),
+}
+
+impl<'a> Display for Activated<'a> {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            Activated::Fd(fd) => write!(fmt, "fd {}", fd),
+            Activated::Name(name) => write!(fmt, "named {}", name),
+        }
+    }
+}
+
+/// An error returned when the configuration asks for a socket that wasn't inherited.
+///
+/// Either there's no such socket, or it was already used as a different type of socket.
+#[derive(Debug, Fail)]
+#[fail(display = "Socket {} not available from socket activation", _0)]
+pub struct NotInherited(String);
+
+#[derive(Default)]
+pub(crate) struct Cache {
+    tcp: HashMap<usize, Arc<TcpListener>>,
+    udp: HashMap<usize, Arc<UdpSocket>>,
+}
+
+/// A socket type that can be taken out of passed file descriptors.
+pub(crate) trait Take: Sized {
+    /// If the socket is expected to be listening.
+    const LISTENS: bool;
+    /// Takes the socket out, checking it is of the right type.
+    fn take(fds: &mut ListenFd, idx: usize) -> IoResult<Option<Self>>;
+}
+
+/// A socket type that can be adopted from the inherited file descriptors.
+pub(crate) trait Adopt: Take {
+    /// The already adopted sockets of this type.
+    fn cache(cache: &mut Cache) -> &mut HashMap<usize, Arc<Self>>;
+}
+
+impl Take for TcpListener {
+    const LISTENS: bool = true;
+    fn take(fds: &mut ListenFd, idx: usize) -> IoResult<Option<Self>> {
+        fds.take_tcp_listener(idx)
+    }
+}
+
+impl Adopt for TcpListener {
+    fn cache(cache: &mut Cache) -> &mut HashMap<usize, Arc<Self>> {
+        &mut cache.tcp
+    }
+}
+
+impl Take for UdpSocket {
+    const LISTENS: bool = false;
+    fn take(fds: &mut ListenFd, idx: usize) -> IoResult<Option<Self>> {
+        fds.take_udp_socket(idx)
+    }
+}
+
+impl Adopt for UdpSocket {
+    fn cache(cache: &mut Cache) -> &mut HashMap<usize, Arc<Self>> {
+        &mut cache.udp
+    }
+}
+
+impl Take for UnixListener {
+    const LISTENS: bool = true;
+    fn take(fds: &mut ListenFd, idx: usize) -> IoResult<Option<Self>> {
+        fds.take_unix_listener(idx)
+    }
+}
+
+pub(crate) struct Inherited {
+    fds: ListenFd,
+    first: RawFd,
+    names: Vec<String>,
+    // Once taken out of the fds, the socket stays here, so the same configuration can be loaded
+    // again (on reload).
+    cache: Cache,
+}
+
+/// Parses the names of the sockets, as passed in `LISTEN_FDNAMES`.
+fn parse_names(names: &str) -> Vec<String> {
+    if names.is_empty() {
+        Vec::new()
+    } else {
+        names.split(':').map(str::to_owned).collect()
+    }
+}
+
+impl Inherited {
+    fn from_env() -> Self {
+        // The listenfd leaves this one in the environment
+        let first = env::var("LISTEN_FDS_FIRST_FD")
+            .ok()
+            .and_then(|first| first.parse().ok())
+            .unwrap_or(LISTEN_FDS_START);
+        let fds = ListenFd::from_env();
+        let names = env::var("LISTEN_FDNAMES")
+            .map(|names| parse_names(&names))
+            .unwrap_or_default();
+        // Don't pass these to our children (the LISTEN_FDS is taken care of by listenfd)
+        env::remove_var("LISTEN_FDS_FIRST_FD");
+        env::remove_var("LISTEN_FDNAMES");
+        debug!(
+            "Inherited {} sockets from {} named {:?}",
+            fds.len(),
+            first,
+            names
+        );
+        Inherited {
+            fds,
+            first,
+            names,
+            cache: Cache::default(),
+        }
+    }
+
+    fn index(&self, which: Activated) -> Result<usize, Error> {
+        let idx = match which {
+            Activated::Fd(fd) if fd >= self.first => Some((fd - self.first) as usize),
+            Activated::Fd(_) => None,
+            Activated::Name(name) => self.names.iter().position(|n| n == name),
+        };
+        match idx {
+            Some(idx) if idx < self.fds.len() => Ok(idx),
+            _ => Err(NotInherited(which.to_string()).into()),
+        }
+    }
+}
+
+lazy_static! {
+    /// The sockets from socket activation.
+    ///
+    /// Initialized (and the variables removed from the environment) when the first helper is set
+    /// up, before the application starts any threads.
+    pub(crate) static ref INHERITED: Mutex<Inherited> = Mutex::new(Inherited::from_env());
+}
+
+/// Adopts an inherited socket.
+///
+/// The same socket may be asked for multiple times (for example after a configuration reload),
+/// all the users share it.
+pub(crate) fn adopt<T: Adopt>(which: Activated) -> Result<Arc<T>, Error> {
+    let mut inherited = INHERITED.lock();
+    let inherited = &mut *inherited;
+    let idx = inherited.index(which)?;
+    let cache = T::cache(&mut inherited.cache);
+    if let Some(socket) = cache.get(&idx) {
+        return Ok(Arc::clone(socket));
+    }
+    let socket = T::take(&mut inherited.fds, idx)?
+        .map(Arc::new)
+        .ok_or_else(|| NotInherited(which.to_string()))?;
+    debug!("Adopted inherited socket {}", which);
+    cache.insert(idx, Arc::clone(&socket));
+    Ok(socket)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    lazy_static! {
+        /// The tests passing sockets through the environment run one at a time.
+        pub(crate) static ref ENV: Mutex<()> = Mutex::new(());
+    }
+
+    #[test]
+    fn names() {
+        assert!(parse_names("").is_empty());
+        assert_eq!(vec!["http"], parse_names("http"));
+        assert_eq!(vec!["http", "", "https"], parse_names("http::https"));
+    }
+
+    /// Pretends to inherit `count` sockets from `first` (they are not taken out in the tests).
+    fn inherited(count: usize, first: Option<RawFd>, names: &str) -> Inherited {
+        let _env = ENV.lock();
+        env::set_var("LISTEN_FDS", count.to_string());
+        match first {
+            Some(first) => env::set_var("LISTEN_FDS_FIRST_FD", first.to_string()),
+            None => env::remove_var("LISTEN_FDS_FIRST_FD"),
+        }
+        env::set_var("LISTEN_FDNAMES", names);
+        env::remove_var("LISTEN_PID");
+        let inherited = Inherited::from_env();
+        assert!(env::var_os("LISTEN_FDS").is_none());
+        assert!(env::var_os("LISTEN_FDS_FIRST_FD").is_none());
+        assert!(env::var_os("LISTEN_FDNAMES").is_none());
+        inherited
+    }
+
+    #[test]
+    fn index() {
+        let inherited = inherited(2, None, "http:https");
+        assert_eq!(0, inherited.index(Activated::Fd(3)).unwrap());
+        assert_eq!(1, inherited.index(Activated::Fd(4)).unwrap());
+        assert!(inherited.index(Activated::Fd(2)).is_err());
+        assert!(inherited.index(Activated::Fd(5)).is_err());
+        assert_eq!(1, inherited.index(Activated::Name("https")).unwrap());
+        assert!(inherited.index(Activated::Name("ftp")).is_err());
+    }
+
+    #[test]
+    fn index_first_fd() {
+        let inherited = inherited(2, Some(10), "");
+        assert!(inherited.index(Activated::Fd(3)).is_err());
+        assert_eq!(0, inherited.index(Activated::Fd(10)).unwrap());
+        assert_eq!(1, inherited.index(Activated::Fd(11)).unwrap());
+        assert!(inherited.index(Activated::Name("http")).is_err());
+    }
+
+    #[test]
+    fn more_names_than_sockets() {
+        let inherited = inherited(1, None, "a:b");
+        assert_eq!(0, inherited.index(Activated::Name("a")).unwrap());
+        assert!(inherited.index(Activated::Name("b")).is_err());
+    }
+}
@@ -0,0 +1,499 @@
+//! Outgoing TCP connections to upstreams.
+
+use std::borrow::Borrow;
+use std::cmp;
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::iter;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use failure::Error;
+use futures::future::{self, Either, Loop};
+use futures::sync::oneshot;
+use serde::Deserialize;
+use spirit::helpers::{CfgHelper, Helper, IteratedCfgHelper};
+use spirit::validation::{Result as ValidationResult, Results as ValidationResults, Unused};
+use spirit::{ArcSwap, Builder, Empty, Spirit};
+use structopt::StructOpt;
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio::timer::{Delay, Timeout};
+
+use super::{Scale, Scaled, Task};
+
+/// An error returned when a host or interface resolves to no address.
+#[derive(Debug, Fail)]
+#[fail(display = "No address found for {}", _0)]
+pub struct NoAddress(pub(crate) String);
+
+/// An error returned when connecting takes longer than the configured timeout.
+#[derive(Debug, Fail)]
+#[fail(display = "Connecting to {} timed out", _0)]
+pub struct ConnectTimeout(SocketAddr);
+
+/// A description of a remote host and port to connect to.
+///
+/// This is the connecting counterpart of [`Listen`](struct.Listen.html).
+///
+/// It contains these configuration options:
+///
+/// * `host` (mandatory): The host name or IP address of the upstream.
+/// * `port` (mandatory): The port to connect to.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Connect {
+    host: String,
+    port: u16,
+}
+
+impl Connect {
+    /// Resolves the address to connect to.
+    ///
+    /// Note that this blocks until the host name is resolved. Inside the runtime, use
+    /// [`resolve_async`](#method.resolve_async).
+    pub fn resolve(&self) -> Result<SocketAddr, Error> {
+        (&self.host as &str, self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| NoAddress(self.to_string()).into())
+    }
+
+    /// Resolves the address without blocking.
+    ///
+    /// The host name is resolved in a separate thread, so this can be used inside the runtime. IP
+    /// addresses are taken right away.
+    pub fn resolve_async(&self) -> impl Future<Item = SocketAddr, Error = Error> + Send {
+        if let Ok(ip) = self.host.parse::<IpAddr>() {
+            return Either::A(future::ok(SocketAddr::new(ip, self.port)));
+        }
+        let (sender, receiver) = oneshot::channel();
+        let connect = self.clone();
+        let resolving = thread::Builder::new()
+            .name("spirit-resolve".to_owned())
+            .spawn(move || {
+                let _ = sender.send(connect.resolve());
+            });
+        let resolved = future::result(resolving)
+            .map_err(Error::from)
+            .and_then(|_| receiver.map_err(|_| format_err!("The resolving thread panicked")))
+            .and_then(|result| result);
+        Either::B(resolved)
+    }
+}
+
+impl Display for Connect {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "{}:{}", self.host, self.port)
+    }
+}
+
+/// How a connection is (re)established, taken from its configuration.
+#[derive(Clone, Debug, PartialEq)]
+struct Reconnect {
+    connect_timeout: Duration,
+    backoff_min: Duration,
+    backoff_max: Duration,
+    keepalive: Option<Duration>,
+}
+
+impl Reconnect {
+    /// How long to wait after the next failed attempt, if this one failed after `backoff`.
+    fn next_backoff(&self, backoff: Duration) -> Duration {
+        cmp::min(backoff * 2, self.backoff_max)
+    }
+}
+
+/// Makes a single connection attempt.
+fn connect_once(
+    connect: &Connect,
+    reconnect: &Reconnect,
+) -> impl Future<Item = TcpStream, Error = Error> {
+    let timeout = reconnect.connect_timeout;
+    let keepalive = reconnect.keepalive;
+    connect
+        .resolve_async()
+        .and_then(move |addr| {
+            Timeout::new(TcpStream::connect(&addr), timeout).map_err(move |e| {
+                if e.is_elapsed() {
+                    ConnectTimeout(addr).into()
+                } else if let Some(e) = e.into_inner() {
+                    e.into()
+                } else {
+                    format_err!("Timer failed when connecting to {}", addr)
+                }
+            })
+        })
+        .and_then(move |stream| {
+            if keepalive.is_some() {
+                stream.set_keepalive(keepalive)?;
+            }
+            Ok(stream)
+        })
+}
+
+/// Keeps a connection to the upstream, handling each one established.
+///
+/// Failed attempts are retried with exponential backoff. Once a connection is closed, a new one
+/// is made after the minimal backoff.
+fn connect_loop<Handle, HandleFut>(
+    connect: Connect,
+    reconnect: Reconnect,
+    name: String,
+    handle: Handle,
+) -> impl Future<Item = (), Error = Error>
+where
+    Handle: FnMut(TcpStream) -> HandleFut,
+    HandleFut: Future<Item = (), Error = Error>,
+{
+    let min = reconnect.backoff_min;
+    future::loop_fn((handle, min), move |(mut handle, backoff)| {
+        let name = name.clone();
+        let next = reconnect.next_backoff(backoff);
+        connect_once(&connect, &reconnect)
+            .then(move |result| match result {
+                Ok(stream) => {
+                    info!("Connected {}", name);
+                    let done = handle(stream).then(move |result| {
+                        match result {
+                            Ok(()) => debug!("Connection {} closed", name),
+                            Err(e) => error!("Failed to handle connection {}: {}", name, e),
+                        }
+                        Ok((handle, min, min))
+                    });
+                    Either::A(done)
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to connect {}, retrying in {:?}: {}",
+                        name, backoff, e
+                    );
+                    Either::B(future::ok((handle, backoff, next)))
+                }
+            })
+            .and_then(|(handle, wait, next)| {
+                Delay::new(Instant::now() + wait)
+                    .map_err(Error::from)
+                    .map(move |()| Loop::<(), _>::Continue((handle, next)))
+            })
+    })
+}
+
+fn default_connect_timeout() -> u64 {
+    10_000
+}
+
+fn default_backoff_min() -> u64 {
+    100
+}
+
+fn default_backoff_max() -> u64 {
+    10_000
+}
+
+/// A configuration fragment of an outgoing TCP connection.
+///
+/// This is the connecting counterpart of [`TcpListen`](struct.TcpListen.html). Instead of
+/// accepting connections, it keeps connections to an upstream open ‒ whenever one gets closed or
+/// fails to be established, a new one is made. Failed attempts are retried with exponential
+/// backoff. If the configuration of a connection changes, the connection is closed and a new one
+/// is made with the new configuration.
+///
+/// The host name is resolved anew on each attempt, in a separate thread.
+///
+/// # Type parameters
+///
+/// * `ExtraCfg`: Any additional configuration options, passed to the action callback. Defaults to
+///   an empty set of parameters. It should be a structure, otherwise spirit can't tell which keys
+///   are unused.
+/// * `ScaleMode`: A description of how many parallel connections to keep (a pool of `scale`
+///   connections by default).
+///
+/// # Configuration options
+///
+/// Aside from the options from the type parameters above, these options are present:
+///
+/// * `host`: Mandatory, the host to connect to.
+/// * `port`: Mandatory, the port to connect to.
+/// * `connect-timeout-ms`: How long a connection attempt may take, in milliseconds. Defaults to
+///   10000.
+/// * `backoff-min-ms`: How long to wait before the first retry, in milliseconds. Each following
+///   failed attempt doubles the time. It is also waited after a connection is closed, before
+///   making a new one. Defaults to 100, must not be 0.
+/// * `backoff-max-ms`: The upper limit of waiting between the attempts, in milliseconds. Defaults
+///   to 10000. It must not be smaller than `backoff-min-ms`.
+/// * `keepalive-ms`: If set, TCP keepalive is turned on for the connections with this interval.
+///   Otherwise the system default is used.
+///
+/// # Examples
+///
+/// ```rust
+/// extern crate failure;
+/// extern crate serde;
+/// #[macro_use]
+/// extern crate serde_derive;
+/// extern crate spirit;
+/// extern crate spirit_tokio;
+/// extern crate tokio;
+///
+/// use std::default::Default;
+///
+/// use failure::Error;
+/// use spirit::{Empty, Spirit, SpiritInner};
+/// use spirit_tokio::TcpConnect;
+/// use tokio::net::TcpStream;
+/// use tokio::prelude::*;
+///
+/// const DEFAULT_CONFIG: &str = r#"
+/// [upstream]
+/// host = "localhost"
+/// port = 1234
+/// "#;
+/// #[derive(Default, Deserialize)]
+/// struct Config {
+///     upstream: TcpConnect,
+/// }
+///
+/// impl Config {
+///     fn upstream(&self) -> TcpConnect {
+///         self.upstream.clone()
+///     }
+/// }
+///
+/// fn connection(_: &SpiritInner<Empty, Config>, conn: TcpStream, _: &Empty) -> impl Future<Item = (), Error = Error> {
+///     tokio::io::write_all(conn, "Hello\n")
+///         .map(|_| ())
+///         .map_err(Error::from)
+/// }
+///
+/// fn main() {
+///     Spirit::<_, Empty, _>::new(Config::default())
+///         .config_defaults(DEFAULT_CONFIG)
+///         .config_helper(Config::upstream, connection, "Upstream")
+///         .run(|spirit| {
+/// #           spirit.terminate();
+///             Ok(())
+///         });
+/// }
+/// ```
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct TcpConnect<ExtraCfg = Empty, ScaleMode: Scaled = Scale> {
+    #[serde(flatten)]
+    connect: Connect,
+    #[serde(flatten)]
+    scale: ScaleMode,
+    #[serde(rename = "connect-timeout-ms", default = "default_connect_timeout")]
+    connect_timeout_ms: u64,
+    #[serde(rename = "backoff-min-ms", default = "default_backoff_min")]
+    backoff_min_ms: u64,
+    #[serde(rename = "backoff-max-ms", default = "default_backoff_max")]
+    backoff_max_ms: u64,
+    #[serde(rename = "keepalive-ms")]
+    keepalive_ms: Option<u64>,
+    #[serde(flatten)]
+    extra_cfg: ExtraCfg,
+    #[serde(flatten)]
+    unused: Unused,
+}
+
+impl<ExtraCfg: Default, ScaleMode: Default + Scaled> Default for TcpConnect<ExtraCfg, ScaleMode> {
+    fn default() -> Self {
+        Self {
+            connect: Connect::default(),
+            scale: ScaleMode::default(),
+            connect_timeout_ms: default_connect_timeout(),
+            backoff_min_ms: default_backoff_min(),
+            backoff_max_ms: default_backoff_max(),
+            keepalive_ms: None,
+            extra_cfg: ExtraCfg::default(),
+            unused: Unused,
+        }
+    }
+}
+
+impl<ExtraCfg, ScaleMode: Scaled> TcpConnect<ExtraCfg, ScaleMode> {
+    /// Checks the reconnection options and turns them into durations.
+    fn reconnect<Name: Display>(&self, name: &Name) -> (Reconnect, ValidationResults) {
+        let mut results = ValidationResults::new();
+        if self.backoff_min_ms == 0 {
+            // Nothing would slow down the retries
+            results.merge(ValidationResult::error(format!(
+                "{} to {}: backoff-min-ms must not be 0",
+                name, self.connect
+            )));
+        }
+        if self.backoff_min_ms > self.backoff_max_ms {
+            results.merge(ValidationResult::error(format!(
+                "{} to {}: backoff-min-ms {} is larger than backoff-max-ms {}",
+                name, self.connect, self.backoff_min_ms, self.backoff_max_ms
+            )));
+        }
+        let reconnect = Reconnect {
+            connect_timeout: Duration::from_millis(self.connect_timeout_ms),
+            backoff_min: Duration::from_millis(self.backoff_min_ms),
+            backoff_max: Duration::from_millis(self.backoff_max_ms),
+            keepalive: self.keepalive_ms.map(Duration::from_millis),
+        };
+        (reconnect, results)
+    }
+}
+
+impl<ExtraCfg: Clone + Debug + PartialEq + Send + 'static> TcpConnect<ExtraCfg> {
+    /// Provides a helper for this configuration.
+    ///
+    /// While you are free to use this directly, it is more commonly used through
+    /// `spirit::Builder::config_helper` with an extractor returning iterator of this type.
+    ///
+    /// # Parameters
+    ///
+    /// * `extract`: Closure that extracts an iterator of `TcpConnect` out of the whole
+    ///   configuration.
+    /// * `conn`: An action to be taken on each established connection. Once the returned future
+    ///   resolves, the connection is considered closed and a new one is made.
+    /// * `name`: How to call the instances in logs.
+    pub fn helper<Extract, ExtractIt, Conn, ConnFut, Name, S, O, C>(
+        mut extract: Extract,
+        conn: Conn,
+        name: Name,
+    ) -> impl Helper<S, O, C>
+    where
+        S: Borrow<ArcSwap<C>> + Sync + Send + 'static,
+        for<'de> C: Deserialize<'de> + Send + Sync + 'static,
+        O: Debug + StructOpt + Sync + Send + 'static,
+        Extract: FnMut(&C) -> ExtractIt + Send + 'static,
+        ExtractIt: IntoIterator<Item = Self>,
+        Conn: Fn(&Arc<Spirit<S, O, C>>, TcpStream, &ExtraCfg) -> ConnFut + Sync + Send + 'static,
+        ConnFut: Future<Item = (), Error = Error> + Send + 'static,
+        Name: Clone + Display + Send + Sync + 'static,
+    {
+        let conn = Arc::new(conn);
+
+        let to_task_name = name.clone();
+        let to_task = move |spirit: &Arc<Spirit<S, O, C>>,
+                            connect: Connect,
+                            (cfg, reconnect): (ExtraCfg, Reconnect)| {
+            let spirit = Arc::clone(spirit);
+            let conn = Arc::clone(&conn);
+            let name = format!("{} to {}", to_task_name, connect);
+            let handle = move |stream| conn(&spirit, stream, &cfg);
+            connect_loop(connect, reconnect, name, handle)
+        };
+
+        let extract_name = name.clone();
+        let extract = move |cfg: &C| {
+            let name = extract_name.clone();
+            extract(cfg).into_iter().map(move |c| {
+                let (scale, mut results) = c.scale.scaled(&name);
+                let (reconnect, reconnect_results) = c.reconnect(&name);
+                results.merge(reconnect_results);
+                (c.connect, (c.extra_cfg, reconnect), scale, results)
+            })
+        };
+
+        Task {
+            extract,
+            build: |connect: &Connect| Ok(connect.clone()),
+            to_task,
+            name,
+        }
+    }
+}
+
+impl<S, O, C, Conn, ConnFut, ExtraCfg> IteratedCfgHelper<S, O, C, Conn> for TcpConnect<ExtraCfg>
+where
+    S: Borrow<ArcSwap<C>> + Sync + Send + 'static,
+    for<'de> C: Deserialize<'de> + Send + Sync + 'static,
+    O: Debug + StructOpt + Sync + Send + 'static,
+    ExtraCfg: Clone + Debug + PartialEq + Send + 'static,
+    Conn: Fn(&Arc<Spirit<S, O, C>>, TcpStream, &ExtraCfg) -> ConnFut + Sync + Send + 'static,
+    ConnFut: Future<Item = (), Error = Error> + Send + 'static,
+{
+    fn apply<Extractor, ExtractedIter, Name>(
+        extractor: Extractor,
+        action: Conn,
+        name: Name,
+        builder: Builder<S, O, C>,
+    ) -> Builder<S, O, C>
+    where
+        Extractor: FnMut(&C) -> ExtractedIter + Send + 'static,
+        ExtractedIter: IntoIterator<Item = Self>,
+        Name: Clone + Display + Send + Sync + 'static,
+    {
+        Self::helper(extractor, action, name).apply(builder)
+    }
+}
+
+impl<S, O, C, Conn, ConnFut, ExtraCfg> CfgHelper<S, O, C, Conn> for TcpConnect<ExtraCfg>
+where
+    S: Borrow<ArcSwap<C>> + Sync + Send + 'static,
+    for<'de> C: Deserialize<'de> + Send + Sync + 'static,
+    O: Debug + StructOpt + Sync + Send + 'static,
+    ExtraCfg: Clone + Debug + PartialEq + Send + 'static,
+    Conn: Fn(&Arc<Spirit<S, O, C>>, TcpStream, &ExtraCfg) -> ConnFut + Sync + Send + 'static,
+    ConnFut: Future<Item = (), Error = Error> + Send + 'static,
+{
+    fn apply<Extractor, Name>(
+        mut extractor: Extractor,
+        action: Conn,
+        name: Name,
+        builder: Builder<S, O, C>,
+    ) -> Builder<S, O, C>
+    where
+        Extractor: FnMut(&C) -> Self + Send + 'static,
+        Name: Clone + Display + Send + Sync + 'static,
+    {
+        let extractor = move |cfg: &_| iter::once(extractor(cfg));
+        Self::helper(extractor, action, name).apply(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use spirit::validation::Level as ValidationLevel;
+
+    use super::*;
+
+    #[test]
+    fn resolve_async() {
+        let ip = Connect {
+            host: "127.0.0.1".to_owned(),
+            port: 1234,
+        };
+        let addr = ip.resolve_async().wait().unwrap();
+        assert_eq!("127.0.0.1:1234".parse::<SocketAddr>().unwrap(), addr);
+        let name = Connect {
+            host: "localhost".to_owned(),
+            port: 1234,
+        };
+        let addr = name.resolve_async().wait().unwrap();
+        assert!(addr.ip().is_loopback());
+        assert_eq!(1234, addr.port());
+    }
+
+    #[test]
+    fn backoff_sequence() {
+        let connect = TcpConnect::<Empty> {
+            backoff_min_ms: 100,
+            backoff_max_ms: 1000,
+            ..TcpConnect::default()
+        };
+        let (reconnect, results) = connect.reconnect(&"test");
+        assert_eq!(None, results.max_level());
+        let next = |backoff: &Duration| Some(reconnect.next_backoff(*backoff));
+        let sequence = iter::successors(Some(reconnect.backoff_min), next)
+            .map(|backoff| backoff.as_millis())
+            .take(7)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![100, 200, 400, 800, 1000, 1000, 1000], sequence);
+    }
+
+    #[test]
+    fn backoff_zero() {
+        let connect = TcpConnect::<Empty> {
+            backoff_min_ms: 0,
+            ..TcpConnect::default()
+        };
+        let (_, results) = connect.reconnect(&"test");
+        assert_eq!(Some(ValidationLevel::Error), results.max_level());
+    }
+}
@@ -9,7 +9,18 @@
 //! A collection of helpers integrating tokio primitives into [spirit]
 //!
 //! The crate provides few helper implementations that handle listening socket auto-reconfiguration
-//! based on configuration.
+//! based on configuration ([`TcpListen`](struct.TcpListen.html),
+//! [`UdpListen`](struct.UdpListen.html) and [`UnixListen`](struct.UnixListen.html)).
+//!
+//! For the other direction, [`TcpConnect`](struct.TcpConnect.html) keeps connections to an
+//! upstream, reconnecting when they are lost or when its configuration changes.
+//!
+//! What happens on the listening sockets can be observed through the
+//! [`metrics`](metrics/index.html). These, together with the state of spirit itself, can be
+//! exported through the [`prometheus`](prometheus/index.html) endpoint.
+//!
+//! With the `tls` feature, there's also [`TlsListen`](struct.TlsListen.html) for TLS on top of
+//! TCP (using OpenSSL).
 //!
 //! # Examples
 //!
@@ -26,7 +37,7 @@
 //!
 //! use failure::Error;
 //! use spirit::{Empty, Spirit, SpiritInner};
-//! use spirit_tokio::TcpListen;
+//! use spirit_tokio::{TcpListen, Tracked};
 //! use tokio::net::TcpStream;
 //! use tokio::prelude::*;
 //!
@@ -45,7 +56,7 @@
 //!     }
 //! }
 //!
-//! fn connection(_: &SpiritInner<Empty, Config>, conn: TcpStream, _: &Empty) -> impl Future<Item = (), Error = Error> {
+//! fn connection(_: &SpiritInner<Empty, Config>, conn: Tracked<TcpStream>, _: &Empty) -> impl Future<Item = (), Error = Error> {
 //!     tokio::io::write_all(conn, "Hello\n")
 //!         .map(|_| ())
 //!         .map_err(Error::from)
@@ -67,10 +78,18 @@
 //!
 //! [spirit]: https://crates.io/crates/spirit.
 
+#[macro_use]
 extern crate failure;
 extern crate futures;
 #[macro_use]
+extern crate lazy_static;
+extern crate listenfd;
+#[macro_use]
 extern crate log;
+extern crate net2;
+extern crate nix;
+#[cfg(feature = "tls")]
+extern crate openssl;
 extern crate parking_lot;
 extern crate serde;
 #[macro_use]
@@ -82,25 +101,60 @@ extern crate tokio;
 
 use std::borrow::Borrow;
 use std::fmt::{Debug, Display};
+use std::io;
 use std::iter;
-use std::net::{TcpListener as StdTcpListener, UdpSocket as StdUdpSocket};
+use std::net::{
+    SocketAddr, TcpListener as StdTcpListener, ToSocketAddrs, UdpSocket as StdUdpSocket,
+};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use failure::Error;
+use futures::future::Either;
 use futures::sync::{mpsc, oneshot};
 use futures::Future;
+use net2::unix::{UnixTcpBuilderExt, UnixUdpBuilderExt};
+use net2::{TcpBuilder, UdpBuilder};
+use nix::ifaddrs;
+use nix::sys::socket::{self, sockopt, SockAddr};
 use parking_lot::Mutex;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use spirit::helpers::{CfgHelper, Helper, IteratedCfgHelper};
-use spirit::validation::{Result as ValidationResult, Results as ValidationResults};
+use spirit::shutdown::Pending;
+use spirit::validation::{
+    self, Level as ValidationLevel, Result as ValidationResult, Results as ValidationResults,
+    Unused,
+};
 use spirit::{ArcSwap, Builder, Empty, Spirit};
 use structopt::StructOpt;
 use tk_listen::ListenExt;
+use tokio::executor::{DefaultExecutor, Executor};
 use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use tokio::prelude::*;
 use tokio::reactor::Handle;
 use tokio::runtime;
+use tokio::timer::Delay;
+
+use activation::Activated;
+use metrics::RunningTask;
+
+pub use activation::NotInherited;
+pub use connect::{Connect, ConnectTimeout, NoAddress, TcpConnect};
+#[cfg(feature = "tls")]
+pub use tls::{NoCertificate, TlsCfg, TlsListen, TlsStream, TlsVersion};
+pub use unix::{InvalidPermissions, ListenUnix, UnixListen};
+pub use upgrade::Upgrade;
+
+mod activation;
+mod connect;
+pub mod metrics;
+pub mod prometheus;
+#[cfg(feature = "tls")]
+mod tls;
+mod unix;
+mod upgrade;
 
 // TODO: Make this public, it may be useful to other helper crates.
 struct RemoteDrop {
@@ -113,9 +167,13 @@ impl Drop for RemoteDrop {
         trace!("Requesting remote drop");
         // Ask the other side to drop the thing
         let _ = self.request_drop.take().unwrap().send(());
-        // And wait for it to actually happen
-        let _ = self.drop_confirmed.take().unwrap().wait();
-        trace!("Remote drop done");
+        // And wait for it to actually happen. But not if we are inside the runtime (when
+        // terminating from within a future, for example) ‒ the drop needs the runtime to make
+        // progress and we would block it.
+        if DefaultExecutor::current().status().is_err() {
+            let _ = self.drop_confirmed.take().unwrap().wait();
+            trace!("Remote drop done");
+        }
     }
 }
 
@@ -130,6 +188,12 @@ impl Drop for RemoteDrop {
 /// tokio runtime where the `to_task` is run on it, to turn it into a future/task to be spawned on
 /// the runtime.
 ///
+/// When the configuration is only [checked], the `build` is not called (the running application
+/// may hold the resources), only the validation results from `extract` are used. It is not called
+/// either for a configuration `extract` already reported an error for.
+///
+/// [checked]: https://docs.rs/spirit/*/spirit/validation/fn.check_only.html
+///
 /// See the bounds on the `Helper` trait implementation for exact signatures.
 pub struct Task<Extract, Build, ToTask, Name> {
     /// A closure used to extract configuration.
@@ -168,6 +232,9 @@ where
             name,
         } = self;
         debug!("Installing helper {}", name);
+        // The sockets passed to us (by socket activation or the previous process on upgrade) are
+        // taken from the environment now, while we're the only thread.
+        upgrade::init();
         // Note: this depends on the specific drop order to avoid races
         struct Install<R, ExtraCfg> {
             resource: R,
@@ -198,6 +265,8 @@ where
                 } = install;
                 let name = installer_name.clone();
                 debug!("Installing resource {} with config {}", name, cfg);
+                let pending = spirit.pending(format!("{} on cfg {}", name, cfg));
+                let running = RunningTask::new(name.to_string());
                 // Get the task itself
                 let task = to_task(&spirit, resource, extra_conf).into_future();
                 let err_name = name.clone();
@@ -209,6 +278,8 @@ where
                     .then(move |orig| {
                         debug!("Terminated resource {} on cfg {}", name, cfg);
                         drop(orig); // Make sure the original future is dropped first.
+                        drop(pending);
+                        drop(running);
                         confirm_drop.send(())
                     })
                     .map_err(|_| ()); // If nobody waits for confirm_drop, that's OK.
@@ -224,6 +295,7 @@ where
             let mut to_send = Vec::new();
             for sub in extract(cfg) {
                 let (sub, extra, mut scale, sub_results) = sub;
+                let unusable = sub_results.max_level() == Some(ValidationLevel::Error);
                 results.merge(sub_results);
 
                 let previous = orig_cache
@@ -233,6 +305,12 @@ where
                 let mut cached = if let Some(previous) = previous {
                     debug!("Reusing previous instance of {} for {:?}", name, sub);
                     previous.clone()
+                } else if validation::check_only() {
+                    debug!("Not creating {} for {:?} when only checking", name, sub);
+                    continue;
+                } else if unusable {
+                    debug!("Not creating {} for unusable {:?}", name, sub);
+                    continue;
                 } else {
                     trace!("Creating new instance of {} for {:?}", name, sub);
                     match build(&sub) {
@@ -327,14 +405,38 @@ where
     }
 }
 
-fn default_host() -> String {
-    "::".to_owned()
+fn default_host() -> Vec<String> {
+    vec!["::".to_owned()]
+}
+
+/// Either a single host or a list of them, as written in the configuration.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Hosts {
+    One(String),
+    Many(Vec<String>),
+}
+
+fn deserialize_hosts<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    Ok(match Hosts::deserialize(deserializer)? {
+        Hosts::One(host) => vec![host],
+        Hosts::Many(hosts) => hosts,
+    })
 }
 
 fn default_scale() -> usize {
     1
 }
 
+fn default_backlog() -> i32 {
+    128
+}
+
+/// An error returned when the listening socket configuration doesn't make sense.
+#[derive(Debug, Fail)]
+#[fail(display = "Invalid listening socket configuration: {}", _0)]
+pub struct InvalidListen(&'static str);
+
 /// A description of listening interface and port.
 ///
 /// This can be used as part of configuration to describe a socket.
@@ -344,41 +446,678 @@ fn default_scale() -> usize {
 ///
 /// It contains these configuration options:
 ///
-/// * `port` (mandatory)
-/// * `host` (optional, if not present, `*` is used)
+/// * `port` (mandatory, unless the socket is inherited)
+/// * `host` (optional, if not present, `*` is used). It can also be a list of hosts, each one
+///   gets its own socket.
+/// * `fd` (optional) to use a socket passed through systemd socket activation instead of binding
+///   a new one. This is the file descriptor number, the first passed socket is `3` (or the one in
+///   `LISTEN_FDS_FIRST_FD`).
+/// * `systemd-name` (optional) to use the socket activated socket with this name (set by
+///   `FileDescriptorName=` in the systemd socket unit).
+/// * `interface-addresses` (optional) to bind to the IP addresses of this network interface (for
+///   example `eth0`) instead of the `host`. The addresses are looked up when the socket is created
+///   (or on each reload with `all-addresses`). The socket is not tied to the interface itself (it
+///   doesn't use `SO_BINDTODEVICE`), so it's not rebound if the addresses change.
+/// * `all-addresses` (optional) to bind each address the hosts (or the interface) resolve to as
+///   a separate socket, for example both `127.0.0.1` and `::1` for `localhost`. By default, only
+///   the first address that can be bound is used. Defaults to `false`. Note that the host names
+///   are then resolved on each configuration reload, blocking it until the resolver answers. A
+///   host that doesn't resolve is a validation error.
+/// * `backlog` (optional) is the length of the queue of connections waiting to be accepted.
+///   Defaults to 128, ignored for UDP.
+/// * `reuse-addr` (optional) sets `SO_REUSEADDR`. Defaults to `true` for TCP and `false` for UDP
+///   (there it lets other sockets bind the same port).
+/// * `reuse-port` (optional) sets `SO_REUSEPORT`, allowing other sockets (possibly in other
+///   processes) to bind the same port. Defaults to `false`.
+/// * `only-v6` (optional) sets `IPV6_V6ONLY` on IPv6 sockets. If not present, the system default
+///   is used.
+/// * `recv-buf-size` and `send-buf-size` (optional) set the sizes of the socket buffers. For TCP,
+///   the accepted connections inherit them.
+///
+/// Only one of `port`, `fd` and `systemd-name` may be present. Inherited sockets are checked to be
+/// of the right type, but they are used as they are (the `host` and the socket options are ignored
+/// for them).
+///
+/// The helpers in this crate set changed buffer sizes on the socket they already have (removing
+/// them from the configuration leaves the last set sizes in place). When any of the other options
+/// change, the socket is bound anew. As the old socket is still open at that point, changing only
+/// the options applied on bind (`backlog`, `reuse-addr`, `reuse-port` and `only-v6`) of the same
+/// port is refused with „Address in use“; these need a change of the port too, or a restart.
+///
+/// The helpers in this crate turn a `Listen` with multiple hosts or addresses into separate
+/// resources (see [`expand`](#method.expand)).
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct Listen {
-    port: u16,
-    #[serde(default = "default_host")]
-    host: String,
+    port: Option<u16>,
+    #[serde(default = "default_host", deserialize_with = "deserialize_hosts")]
+    host: Vec<String>,
+    fd: Option<RawFd>,
+    #[serde(rename = "systemd-name")]
+    systemd_name: Option<String>,
+    #[serde(rename = "interface-addresses")]
+    interface_addresses: Option<String>,
+    #[serde(rename = "all-addresses", default)]
+    all_addresses: bool,
+    #[serde(default = "default_backlog")]
+    backlog: i32,
+    #[serde(rename = "reuse-addr")]
+    reuse_addr: Option<bool>,
+    #[serde(rename = "reuse-port", default)]
+    reuse_port: bool,
+    #[serde(rename = "only-v6")]
+    only_v6: Option<bool>,
+    #[serde(rename = "recv-buf-size")]
+    recv_buf_size: Option<usize>,
+    #[serde(rename = "send-buf-size")]
+    send_buf_size: Option<usize>,
 }
 
 impl Default for Listen {
     fn default() -> Self {
         Listen {
-            port: 0,
+            port: Some(0),
             host: default_host(),
+            fd: None,
+            systemd_name: None,
+            interface_addresses: None,
+            all_addresses: false,
+            backlog: default_backlog(),
+            reuse_addr: None,
+            reuse_port: false,
+            only_v6: None,
+            recv_buf_size: None,
+            send_buf_size: None,
         }
     }
 }
 
+/// Where a listening socket comes from.
+enum Source<'a> {
+    Bind(u16),
+    Inherit(Activated<'a>),
+}
+
 impl Listen {
+    fn source<'a>(&'a self) -> Result<Source<'a>, Error> {
+        match (self.port, self.fd, self.systemd_name.as_ref()) {
+            (Some(port), None, None) => Ok(Source::Bind(port)),
+            (None, Some(fd), None) => Ok(Source::Inherit(Activated::Fd(fd))),
+            (None, None, Some(name)) => Ok(Source::Inherit(Activated::Name(name))),
+            (None, None, None) => Err(InvalidListen("missing port").into()),
+            _ => Err(InvalidListen("only one of port, fd and systemd-name is allowed").into()),
+        }
+    }
+
+    /// Identifies the socket when passing it to a new process on upgrade.
+    fn upgrade_key(&self, kind: &str) -> Result<String, Error> {
+        Ok(match self.source()? {
+            Source::Bind(port) => format!("{} {}:{}", kind, self.host.join(","), port),
+            Source::Inherit(activated) => format!("{} {}", kind, activated),
+        })
+    }
+
+    /// The addresses to bind to, in the order to try them.
+    fn addresses(&self, port: u16) -> Result<Vec<SocketAddr>, Error> {
+        let addresses: Vec<SocketAddr> = match self.interface_addresses {
+            Some(ref interface) => ifaddrs::getifaddrs()?
+                .filter(|ifaddr| &ifaddr.interface_name == interface)
+                .filter_map(|ifaddr| match ifaddr.address {
+                    Some(SockAddr::Inet(addr)) => Some(addr.to_std()),
+                    _ => None,
+                })
+                .map(|mut addr| {
+                    addr.set_port(port);
+                    addr
+                })
+                .collect(),
+            None => {
+                let mut addresses = Vec::new();
+                for host in &self.host {
+                    let resolved = (host as &str, port)
+                        .to_socket_addrs()
+                        .map_err(|e| format_err!("Can't resolve {}: {}", host, e))?;
+                    addresses.extend(resolved);
+                }
+                addresses
+            }
+        };
+        if addresses.is_empty() {
+            let name = match self.interface_addresses {
+                Some(ref interface) => interface.clone(),
+                None => self.host.join(", "),
+            };
+            return Err(NoAddress(name).into());
+        }
+        Ok(addresses)
+    }
+
+    /// Splits the configuration into one for each socket to be bound.
+    ///
+    /// Each of the hosts gets its own configuration. If `all-addresses` is set, each of the
+    /// addresses they resolve to gets its own instead (IPv6 link-local addresses are skipped in
+    /// that case). Inherited sockets are left as they are.
+    ///
+    /// Note that this blocks until the host names are resolved. The helpers in this crate call it
+    /// on every configuration reload, from the validator.
+    pub fn expand(&self) -> Result<Vec<Listen>, Error> {
+        let port = match self.source()? {
+            Source::Bind(port) => port,
+            Source::Inherit(_) => return Ok(vec![self.clone()]),
+        };
+        if self.all_addresses {
+            let expanded = self
+                .addresses(port)?
+                .into_iter()
+                .filter(|addr| match *addr {
+                    SocketAddr::V6(ref addr) if addr.scope_id() != 0 => {
+                        debug!("Skipping link-local address {}", addr);
+                        false
+                    }
+                    _ => true,
+                })
+                .map(|addr| Listen {
+                    host: vec![addr.ip().to_string()],
+                    interface_addresses: None,
+                    all_addresses: false,
+                    ..self.clone()
+                })
+                .collect();
+            Ok(expanded)
+        } else if self.interface_addresses.is_some() {
+            Ok(vec![self.clone()])
+        } else {
+            let expanded = self
+                .host
+                .iter()
+                .map(|host| Listen {
+                    host: vec![host.clone()],
+                    ..self.clone()
+                })
+                .collect();
+            Ok(expanded)
+        }
+    }
+
+    /// Binds the first of the addresses that works.
+    fn bind<R, B>(&self, port: u16, mut bind: B) -> Result<R, Error>
+    where
+        B: FnMut(&SocketAddr) -> Result<R, Error>,
+    {
+        let mut error = None;
+        for addr in self.addresses(port)? {
+            match bind(&addr) {
+                Ok(bound) => return Ok(bound),
+                Err(e) => {
+                    debug!("Failed to bind {}: {}", addr, e);
+                    error = Some(format_err!("Can't bind {}: {}", addr, e));
+                }
+            }
+        }
+        Err(error.expect("At least one address is always present"))
+    }
+
+    fn buffers(&self) -> Buffers {
+        Buffers {
+            recv: self.recv_buf_size,
+            send: self.send_buf_size,
+        }
+    }
+
+    fn bind_tcp(&self, addr: &SocketAddr) -> Result<StdTcpListener, Error> {
+        let builder = match *addr {
+            SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+            SocketAddr::V6(_) => {
+                let builder = TcpBuilder::new_v6()?;
+                if let Some(only_v6) = self.only_v6 {
+                    builder.only_v6(only_v6)?;
+                }
+                builder
+            }
+        };
+        builder
+            .reuse_address(self.reuse_addr.unwrap_or(true))?
+            .reuse_port(self.reuse_port)?;
+        self.buffers().apply(&builder)?;
+        builder.bind(addr)?;
+        Ok(builder.listen(self.backlog)?)
+    }
+
+    fn bind_udp(&self, addr: &SocketAddr) -> Result<StdUdpSocket, Error> {
+        let builder = match *addr {
+            SocketAddr::V4(_) => UdpBuilder::new_v4()?,
+            SocketAddr::V6(_) => {
+                let builder = UdpBuilder::new_v6()?;
+                if let Some(only_v6) = self.only_v6 {
+                    builder.only_v6(only_v6)?;
+                }
+                builder
+            }
+        };
+        builder
+            .reuse_address(self.reuse_addr.unwrap_or(false))?
+            .reuse_port(self.reuse_port)?;
+        self.buffers().apply(&builder)?;
+        Ok(builder.bind(addr)?)
+    }
+
     /// Creates a TCP socket described by the loaded configuration.
+    ///
+    /// If the socket was passed from the previous process during an [upgrade](struct.Upgrade.html),
+    /// it is used instead.
     pub fn create_tcp(&self) -> Result<Arc<StdTcpListener>, Error> {
-        Ok(Arc::new(StdTcpListener::bind((
-            &self.host as &str,
-            self.port,
-        ))?))
+        let key = self.upgrade_key("tcp")?;
+        let listener = match (upgrade::adopt(&key)?, self.source()?) {
+            (Some(listener), _) => Arc::new(listener),
+            (None, Source::Inherit(activated)) => activation::adopt(activated)?,
+            (None, Source::Bind(port)) => Arc::new(self.bind(port, |addr| self.bind_tcp(addr))?),
+        };
+        upgrade::register(key, &listener);
+        Ok(listener)
     }
     /// Creates a UDP socket described by the loaded configuration.
+    ///
+    /// If the socket was passed from the previous process during an [upgrade](struct.Upgrade.html),
+    /// it is used instead.
     pub fn create_udp(&self) -> Result<Arc<StdUdpSocket>, Error> {
-        Ok(Arc::new(StdUdpSocket::bind((
-            &self.host as &str,
-            self.port,
-        ))?))
+        let key = self.upgrade_key("udp")?;
+        let socket = match (upgrade::adopt(&key)?, self.source()?) {
+            (Some(socket), _) => Arc::new(socket),
+            (None, Source::Inherit(activated)) => activation::adopt(activated)?,
+            (None, Source::Bind(port)) => Arc::new(self.bind(port, |addr| self.bind_udp(addr))?),
+        };
+        upgrade::register(key, &socket);
+        Ok(socket)
+    }
+}
+
+/// Sizes of the socket buffers.
+///
+/// These can be changed on a live socket, so they are not part of the configuration identifying
+/// the socket, but set each time a task on it starts.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Buffers {
+    recv: Option<usize>,
+    send: Option<usize>,
+}
+
+impl Buffers {
+    fn apply<S: AsRawFd>(&self, sock: &S) -> Result<(), Error> {
+        if let Some(size) = self.recv {
+            socket::setsockopt(sock.as_raw_fd(), sockopt::RcvBuf, &size)?;
+        }
+        if let Some(size) = self.send {
+            socket::setsockopt(sock.as_raw_fd(), sockopt::SndBuf, &size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Turns one listener configuration into one instance for each socket (see `Listen::expand`).
+///
+/// The buffer sizes are moved from the listener configuration to the extra one.
+fn expanded<Extra: Clone>(
+    listen: &Listen,
+    extra: Extra,
+    scale: usize,
+    mut results: ValidationResults,
+) -> Vec<(Listen, (Extra, Buffers), usize, ValidationResults)> {
+    let buffers = listen.buffers();
+    let listen = Listen {
+        recv_buf_size: None,
+        send_buf_size: None,
+        ..listen.clone()
+    };
+    // The unexpanded configuration carries the error, so it is not created.
+    let listens = listen.expand().unwrap_or_else(|e| {
+        let msg = format!("Can't use {:?}: {}", listen, e);
+        results.merge(ValidationResult::error(msg));
+        vec![listen.clone()]
+    });
+    let mut results = Some(results);
+    listens
+        .into_iter()
+        .map(|listen| {
+            let results = results.take().unwrap_or_default();
+            (listen, (extra.clone(), buffers.clone()), scale, results)
+        })
+        .collect()
+}
+
+/// How a listener accepts connections, taken from its configuration.
+#[derive(Clone, Debug, PartialEq)]
+struct Accept {
+    error_sleep: Duration,
+    max_conn: usize,
+    drain_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+}
+
+/// Options set on each accepted TCP connection.
+#[derive(Clone, Debug, PartialEq)]
+struct StreamOpts {
+    nodelay: bool,
+    keepalive: Option<Duration>,
+}
+
+impl StreamOpts {
+    fn apply<Name: Display>(&self, stream: &TcpStream, name: &Name) {
+        let result = stream
+            .set_nodelay(self.nodelay)
+            .and_then(|()| stream.set_keepalive(self.keepalive));
+        if let Err(e) = result {
+            warn!("Failed to set options of connection on {}: {}", name, e);
+        }
+    }
+}
+
+/// The connections accepted by one listener instance.
+struct Connections {
+    active: AtomicUsize,
+    /// Notified when the last connection finishes, once draining started.
+    drained: Mutex<Option<oneshot::Sender<()>>>,
+    /// Registered for the shutdown as long as the listener or any of its connections live.
+    _pending: Pending,
+}
+
+impl Connections {
+    fn finished(&self) {
+        if self.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            if let Some(drained) = self.drained.lock().take() {
+                let _ = drained.send(());
+            }
+        }
+    }
+}
+
+/// Drains the connections once the listener is gone.
+///
+/// This lives inside the listener future, so it gets dropped together with it (when the listener
+/// is removed or reconfigured).
+struct Drain<Name: Display + Send + 'static> {
+    name: Name,
+    timeout: Option<Duration>,
+    connections: Arc<Connections>,
+    // Sending cuts the connections, dropping it leaves them alone.
+    cut: Option<oneshot::Sender<()>>,
+}
+
+impl<Name: Display + Send + 'static> Drop for Drain<Name> {
+    fn drop(&mut self) {
+        let cut = self.cut.take().expect("Drain dropped twice");
+        let active = self.connections.active.load(Ordering::SeqCst);
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => {
+                if active > 0 {
+                    debug!(
+                        "Listener {} gone, leaving {} connections be",
+                        self.name, active
+                    );
+                }
+                return;
+            }
+        };
+        let (drained_send, drained_recv) = oneshot::channel();
+        *self.connections.drained.lock() = Some(drained_send);
+        // Checked after setting the drained notification, so we don't miss the last one
+        let active = self.connections.active.load(Ordering::SeqCst);
+        if active == 0 {
+            return;
+        }
+        info!(
+            "Listener {} gone, draining {} connections",
+            self.name, active
+        );
+        let name = self.name.to_string();
+        let connections = Arc::clone(&self.connections);
+        let drain = drained_recv
+            .select2(Delay::new(Instant::now() + timeout))
+            .then(move |result| {
+                match result {
+                    Ok(Either::A(_)) | Err(Either::A(_)) => {
+                        debug!("Connections on {} drained", name);
+                    }
+                    Ok(Either::B(_)) | Err(Either::B(_)) => {
+                        let active = connections.active.load(Ordering::SeqCst);
+                        warn!(
+                            "Cutting {} connections on {} after drain timeout",
+                            active, name
+                        );
+                        let _ = cut.send(());
+                    }
+                }
+                Ok(())
+            });
+        if let Err(e) = DefaultExecutor::current().spawn(Box::new(drain)) {
+            warn!("Can't drain connections on {}: {}", self.name, e);
+        }
     }
 }
 
+/// An accepted connection, keeping track of when it was last active.
+///
+/// The listeners hand the accepted connections to the callbacks wrapped in this. Reading or
+/// writing at least one byte counts as activity for the `idle-timeout-ms` option; the connection
+/// waking up for other reasons doesn't.
+#[derive(Debug)]
+pub struct Tracked<S> {
+    inner: S,
+    activity: Arc<Mutex<Instant>>,
+}
+
+impl<S> Tracked<S> {
+    /// Wraps the connection, considering it active right now.
+    pub fn new(inner: S) -> Self {
+        Tracked {
+            inner,
+            activity: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Returns the wrapped connection.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns the wrapped connection.
+    ///
+    /// The bytes transferred through it directly don't count as activity.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Unwraps the connection.
+    ///
+    /// The connection is considered idle from then on.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn active(&self, transferred: usize) {
+        if transferred > 0 {
+            *self.activity.lock() = Instant::now();
+        }
+    }
+}
+
+impl<S: Read> Read for Tracked<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.active(read);
+        Ok(read)
+    }
+}
+
+impl<S: Write> Write for Tracked<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.active(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Tracked<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for Tracked<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+/// Closes a connection that is idle or open for too long.
+///
+/// The connection is considered idle if no bytes went through its `Tracked` stream for the idle
+/// timeout.
+struct Timeouts<F, Name> {
+    inner: F,
+    name: Name,
+    activity: Arc<Mutex<Instant>>,
+    idle: Option<(Duration, Delay)>,
+    lifetime: Option<Delay>,
+}
+
+impl<F, Name> Timeouts<F, Name> {
+    fn new(
+        inner: F,
+        name: Name,
+        activity: Arc<Mutex<Instant>>,
+        idle: Option<Duration>,
+        lifetime: Option<Duration>,
+    ) -> Self {
+        let now = Instant::now();
+        Self {
+            inner,
+            name,
+            activity,
+            idle: idle.map(|idle| (idle, Delay::new(now + idle))),
+            lifetime: lifetime.map(|lifetime| Delay::new(now + lifetime)),
+        }
+    }
+}
+
+impl<F, Name> Future for Timeouts<F, Name>
+where
+    F: Future<Item = (), Error = Error>,
+    Name: Display,
+{
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<(), Error> {
+        if self.inner.poll()?.is_ready() {
+            return Ok(Async::Ready(()));
+        }
+        if let Some(ref mut lifetime) = self.lifetime {
+            if lifetime.poll()?.is_ready() {
+                warn!("Closing connection on {}, open for too long", self.name);
+                return Ok(Async::Ready(()));
+            }
+        }
+        if let Some((timeout, ref mut idle)) = self.idle {
+            // The deadline is moved only once it is reached, by the activity that happened since.
+            while idle.poll()?.is_ready() {
+                let deadline = *self.activity.lock() + timeout;
+                if deadline <= Instant::now() {
+                    warn!("Closing connection on {}, idle for too long", self.name);
+                    return Ok(Async::Ready(()));
+                }
+                idle.reset(deadline);
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// Accepts connections on a listener, handling each one in a separate task.
+fn accept_loop<Incoming, Handle, HandleFut, Name>(
+    incoming: Incoming,
+    accept: Accept,
+    name: Name,
+    pending: Pending,
+    mut handle: Handle,
+) -> impl Future<Item = (), Error = Error>
+where
+    Incoming: Stream<Error = io::Error>,
+    Handle: FnMut(Tracked<Incoming::Item>) -> HandleFut,
+    HandleFut: Future<Item = (), Error = Error> + Send + 'static,
+    Name: Clone + Display + Send + 'static,
+{
+    let connections = Arc::new(Connections {
+        active: AtomicUsize::new(0),
+        drained: Mutex::new(None),
+        _pending: pending,
+    });
+    let (cut_send, cut_recv) = oneshot::channel();
+    // If the sender is dropped without sending, the connections are not to be cut.
+    let cut = cut_recv.shared();
+    let drain = Drain {
+        name: name.clone(),
+        timeout: accept.drain_timeout,
+        connections: Arc::clone(&connections),
+        cut: Some(cut_send),
+    };
+    let idle_timeout = accept.idle_timeout;
+    let max_conn = accept.max_conn;
+    let max_lifetime = accept.max_lifetime;
+    let label = name.to_string();
+    let error_label = label.clone();
+    incoming
+        .map_err(move |e| {
+            metrics::report(|m| m.accept_error(&error_label));
+            e
+        })
+        // Handle errors like too many open FDs gracefully
+        .sleep_on_error(accept.error_sleep)
+        .map(move |new_conn| {
+            // Keep it alive as long as the listener
+            let _ = &drain;
+            let name = name.clone();
+            let label = label.clone();
+            let connections = Arc::clone(&connections);
+            let active = connections.active.fetch_add(1, Ordering::SeqCst) + 1;
+            metrics::report(|m| {
+                m.accepted(&label);
+                if active >= max_conn {
+                    m.max_conn_reached(&label);
+                }
+            });
+            let cut = cut.clone().then(|result| match result {
+                Ok(_) => Either::A(future::ok(())),
+                Err(_) => Either::B(future::empty()),
+            });
+            // The listen below keeps track of how many parallel connections there are. But it
+            // does so inside the same future, which prevents the separate connections to be
+            // handled in parallel on a thread pool. So we spawn the future to handle the
+            // connection itself. But we want to keep the future alive so the listen doesn't think
+            // it already terminated, therefore the done-channel.
+            let (done_send, done_recv) = oneshot::channel();
+            let new_conn = Tracked::new(new_conn);
+            let activity = Arc::clone(&new_conn.activity);
+            let conn = handle(new_conn);
+            let conn = Timeouts::new(conn, name.clone(), activity, idle_timeout, max_lifetime);
+            let handle_conn = conn.select(cut).then(move |r| {
+                if let Err((e, _)) = r {
+                    error!("Failed to handle connection on {}: {}", name, e);
+                    metrics::report(|m| m.handler_error(&label));
+                }
+                metrics::report(|m| m.closed(&label));
+                connections.finished();
+                // Ignore the other side going away. This may happen if the listener terminated,
+                // but the connection lingers for longer.
+                let _ = done_send.send(());
+                future::ok(())
+            });
+            tokio::spawn(handle_conn);
+            done_recv.then(|_| future::ok(()))
+        })
+        .listen(max_conn)
+        .map_err(|()| unreachable!("tk-listen never errors"))
+}
+
 fn default_error_sleep() -> u64 {
     100
 }
@@ -457,20 +1196,38 @@ impl Scaled for Singleton {
 /// # Type parameters
 ///
 /// * `ExtraCfg`: Any additional configuration options, passed to the action callback. Defaults to
-///   an empty set of parameters.
+///   an empty set of parameters. It should be a structure, otherwise spirit can't tell which keys
+///   are unused (see [`Unused`](https://docs.rs/spirit/*/spirit/validation/struct.Unused.html)).
 /// * `ScaleMode`: A description of how to scale into multiple listening instances.
 ///
 /// # Configuration options
 ///
 /// Aside from the options from the type parameters above, these options are present:
 ///
-/// * `host`: The host/interface to listen on, defaults to `*`.
+/// * `host`: The host to listen on (or a list of them), defaults to `*`.
 /// * `port`: Mandatory, the port to listen to.
-/// * `error_sleep_ms`: If there's a recoverable error like „Too many open files“, this many
+/// * `fd` or `systemd-name`: Use a socket passed through systemd socket activation instead of the
+///   `port` (see [`Listen`](struct.Listen.html)).
+/// * `interface-addresses`, `all-addresses`, `backlog`, `reuse-addr`, `reuse-port`, `only-v6`,
+///   `recv-buf-size` and `send-buf-size`: Options of the listening socket (see
+///   [`Listen`](struct.Listen.html)).
+/// * `nodelay`: Sets `TCP_NODELAY` on the accepted connections. Defaults to `false`.
+/// * `keepalive-ms`: If set, TCP keepalive is turned on for the accepted connections with this
+///   interval. Changing this or the `nodelay` keeps the listening socket, the new values apply to
+///   the connections accepted from then on.
+/// * `error-sleep-ms`: If there's a recoverable error like „Too many open files“, this many
 ///   milliseconds is waited before trying to accept more connections. Defaults to 100.
-/// * `max_conn`: Maximum number of parallel connections. This is per one instance, therefore the
+/// * `max-conn`: Maximum number of parallel connections. This is per one instance, therefore the
 ///   total number of connections being handled is `scale * max_conn` (if scaling is enabled).
 ///   Defaults to 1000.
+/// * `drain-timeout-ms`: When the listener is removed or reconfigured (or the application
+///   terminates), it stops accepting new connections. The ones already accepted are given this
+///   many milliseconds to finish, then they are closed. If not set, they are left to run until
+///   they finish on their own.
+/// * `idle-timeout-ms`: A connection that reads or writes nothing for this many milliseconds is
+///   closed, so stuck clients don't hold the `max-conn` slots forever. Not limited by default.
+/// * `max-conn-lifetime-ms`: A connection is closed after being open for this many milliseconds,
+///   active or not. Not limited by default.
 ///
 /// # Example
 ///
@@ -485,8 +1242,20 @@ pub struct TcpListen<ExtraCfg = Empty, ScaleMode: Scaled = Scale> {
     error_sleep_ms: u64,
     #[serde(rename = "max-conn", default = "default_max_conn")]
     max_conn: usize,
+    #[serde(rename = "drain-timeout-ms")]
+    drain_timeout_ms: Option<u64>,
+    #[serde(rename = "idle-timeout-ms")]
+    idle_timeout_ms: Option<u64>,
+    #[serde(rename = "max-conn-lifetime-ms")]
+    max_conn_lifetime_ms: Option<u64>,
+    #[serde(default)]
+    nodelay: bool,
+    #[serde(rename = "keepalive-ms")]
+    keepalive_ms: Option<u64>,
     #[serde(flatten)]
     extra_cfg: ExtraCfg,
+    #[serde(flatten)]
+    unused: Unused,
 }
 
 impl<ExtraCfg: Default, ScaleMode: Default + Scaled> Default for TcpListen<ExtraCfg, ScaleMode> {
@@ -496,7 +1265,22 @@ impl<ExtraCfg: Default, ScaleMode: Default + Scaled> Default for TcpListen<Extra
             scale: ScaleMode::default(),
             error_sleep_ms: default_error_sleep(),
             max_conn: default_max_conn(),
+            drain_timeout_ms: None,
+            idle_timeout_ms: None,
+            max_conn_lifetime_ms: None,
+            nodelay: false,
+            keepalive_ms: None,
             extra_cfg: ExtraCfg::default(),
+            unused: Unused,
+        }
+    }
+}
+
+impl<ExtraCfg, ScaleMode: Scaled> TcpListen<ExtraCfg, ScaleMode> {
+    fn stream_opts(&self) -> StreamOpts {
+        StreamOpts {
+            nodelay: self.nodelay,
+            keepalive: self.keepalive_ms.map(Duration::from_millis),
         }
     }
 }
@@ -524,7 +1308,10 @@ impl<ExtraCfg: Clone + Debug + PartialEq + Send + 'static> TcpListen<ExtraCfg> {
         O: Debug + StructOpt + Sync + Send + 'static,
         Extract: FnMut(&C) -> ExtractIt + Send + 'static,
         ExtractIt: IntoIterator<Item = Self>,
-        Conn: Fn(&Arc<Spirit<S, O, C>>, TcpStream, &ExtraCfg) -> ConnFut + Sync + Send + 'static,
+        Conn: Fn(&Arc<Spirit<S, O, C>>, Tracked<TcpStream>, &ExtraCfg) -> ConnFut
+            + Sync
+            + Send
+            + 'static,
         ConnFut: Future<Item = (), Error = Error> + Send + 'static,
         Name: Clone + Display + Send + Sync + 'static,
     {
@@ -534,53 +1321,45 @@ impl<ExtraCfg: Clone + Debug + PartialEq + Send + 'static> TcpListen<ExtraCfg> {
         let to_task =
             move |spirit: &Arc<Spirit<S, O, C>>,
                   listener: Arc<StdTcpListener>,
-                  (cfg, error_sleep, max_conn): (ExtraCfg, Duration, usize)| {
+                  extra: ((ExtraCfg, Accept, StreamOpts), Buffers)| {
+                let ((cfg, accept, stream_opts), buffers) = extra;
                 let spirit = Arc::clone(spirit);
                 let conn = Arc::clone(&conn);
                 let name = to_task_name.clone();
-                listener
-                    .try_clone() // Another copy of the listener
-                    // std → tokio socket conversion
-                    .and_then(|listener| TcpListener::from_std(listener, &Handle::default()))
+                let pending = spirit.pending(format!("connections on {}", name));
+                buffers
+                    .apply(&*listener)
+                    .and_then(|()| {
+                        // Another copy of the listener, std → tokio socket conversion
+                        let listener = listener.try_clone()?;
+                        Ok(TcpListener::from_std(listener, &Handle::default())?)
+                    })
                     .into_future()
                     .and_then(move |listener| {
-                        listener.incoming()
-                            // Handle errors like too many open FDs gracefully
-                            .sleep_on_error(error_sleep)
-                            .map(move |new_conn| {
-                                let name = name.clone();
-                                // The listen below keeps track of how many parallel connections
-                                // there are. But it does so inside the same future, which prevents
-                                // the separate connections to be handled in parallel on a thread
-                                // pool. So we spawn the future to handle the connection itself.
-                                // But we want to keep the future alive so the listen doesn't think
-                                // it already terminated, therefore the done-channel.
-                                let (done_send, done_recv) = oneshot::channel();
-                                let handle_conn = conn(&spirit, new_conn, &cfg)
-                                    .then(move |r| {
-                                        if let Err(e) = r {
-                                            error!("Failed to handle connection on {}: {}", name, e);
-                                        }
-                                        // Ignore the other side going away. This may happen if the
-                                        // listener terminated, but the connection lingers for
-                                        // longer.
-                                        let _ = done_send.send(());
-                                        future::ok(())
-                                    });
-                                tokio::spawn(handle_conn);
-                                done_recv.then(|_| future::ok(()))
-                            })
-                            .listen(max_conn)
-                            .map_err(|()| unreachable!("tk-listen never errors"))
-                    }).map_err(Error::from)
+                        let conn_name = name.clone();
+                        let handle = move |new_conn: Tracked<TcpStream>| {
+                            stream_opts.apply(new_conn.get_ref(), &conn_name);
+                            conn(&spirit, new_conn, &cfg)
+                        };
+                        accept_loop(listener.incoming(), accept, name, pending, handle)
+                    })
             };
 
         let extract_name = name.clone();
         let extract = move |cfg: &C| {
-            extract(cfg).into_iter().map(|c| {
-                let (scale, results) = c.scale.scaled(&extract_name);
-                let sleep = Duration::from_millis(c.error_sleep_ms);
-                (c.listen, (c.extra_cfg, sleep, c.max_conn), scale, results)
+            let name = extract_name.clone();
+            extract(cfg).into_iter().flat_map(move |c| {
+                let (scale, results) = c.scale.scaled(&name);
+                let accept = Accept {
+                    error_sleep: Duration::from_millis(c.error_sleep_ms),
+                    max_conn: c.max_conn,
+                    drain_timeout: c.drain_timeout_ms.map(Duration::from_millis),
+                    idle_timeout: c.idle_timeout_ms.map(Duration::from_millis),
+                    max_lifetime: c.max_conn_lifetime_ms.map(Duration::from_millis),
+                };
+                let stream_opts = c.stream_opts();
+                let extra = (c.extra_cfg, accept, stream_opts);
+                expanded(&c.listen, extra, scale, results)
             })
         };
 
@@ -599,7 +1378,8 @@ where
     for<'de> C: Deserialize<'de> + Send + Sync + 'static,
     O: Debug + StructOpt + Sync + Send + 'static,
     ExtraCfg: Clone + Debug + PartialEq + Send + 'static,
-    Conn: Fn(&Arc<Spirit<S, O, C>>, TcpStream, &ExtraCfg) -> ConnFut + Sync + Send + 'static,
+    Conn:
+        Fn(&Arc<Spirit<S, O, C>>, Tracked<TcpStream>, &ExtraCfg) -> ConnFut + Sync + Send + 'static,
     ConnFut: Future<Item = (), Error = Error> + Send + 'static,
 {
     fn apply<Extractor, ExtractedIter, Name>(
@@ -623,7 +1403,8 @@ where
     for<'de> C: Deserialize<'de> + Send + Sync + 'static,
     O: Debug + StructOpt + Sync + Send + 'static,
     ExtraCfg: Clone + Debug + PartialEq + Send + 'static,
-    Conn: Fn(&Arc<Spirit<S, O, C>>, TcpStream, &ExtraCfg) -> ConnFut + Sync + Send + 'static,
+    Conn:
+        Fn(&Arc<Spirit<S, O, C>>, Tracked<TcpStream>, &ExtraCfg) -> ConnFut + Sync + Send + 'static,
     ConnFut: Future<Item = (), Error = Error> + Send + 'static,
 {
     fn apply<Extractor, Name>(
@@ -650,7 +1431,7 @@ where
 /// # Type parameters
 ///
 /// * `ExtraCfg`: Extra options folded into this configuration, for application specific options.
-///   They are passed to the action.
+///   They are passed to the action. Like with `TcpListen`, it should be a structure.
 /// * `ScaleMode`: How scaling should be done. If scaling is enabled, the action should handle
 ///   situation where it runs in multiple instances. However, even in case scaling is disabled, the
 ///   action needs to handle being „restarted“ ‒ if there's a new configuration for the socket, the
@@ -660,10 +1441,12 @@ where
 ///
 /// In addition to options provided by the above type parameters, these are present:
 ///
-/// * `host`: The hostname/interface to bind to. Defaults to `*`.
+/// * `host`: The hostname to bind to (or a list of them). Defaults to `*`.
 /// * `port`: The port to bind the UDP socket to (mandatory). While it is possible to create
 ///   unbound UDP sockets with an OS-assigned port, these don't need the configuration and are not
 ///   created by this configuration fragment.
+/// * `fd` or `systemd-name`: Use a socket passed through systemd socket activation instead of the
+///   `port` (see [`Listen`](struct.Listen.html)).
 ///
 /// #
 #[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -674,6 +1457,8 @@ pub struct UdpListen<ExtraCfg = Empty, ScaleMode: Scaled = Scale> {
     scale: ScaleMode,
     #[serde(flatten)]
     extra_cfg: ExtraCfg,
+    #[serde(flatten)]
+    unused: Unused,
 }
 
 impl<ExtraCfg: Clone + Debug + PartialEq + Send + 'static> UdpListen<ExtraCfg> {
@@ -700,26 +1485,36 @@ impl<ExtraCfg: Clone + Debug + PartialEq + Send + 'static> UdpListen<ExtraCfg> {
         let action = Arc::new(action);
 
         let to_task_name = name.clone();
-        let to_task =
-            move |spirit: &Arc<Spirit<S, O, C>>, socket: Arc<StdUdpSocket>, cfg: ExtraCfg| {
-                trace!("Running UDP listener {} for {:?}", to_task_name, cfg);
-                let spirit = Arc::clone(spirit);
-                let action = Arc::clone(&action);
-                socket
-                    .try_clone() // Another copy of the listener
-                    // std → tokio socket conversion
-                    .and_then(|socket| UdpSocket::from_std(socket, &Handle::default()))
-                    .map_err(Error::from)
-                    .into_future()
-                    .and_then(move |socket| action(&spirit, socket, &cfg))
-            };
+        let to_task = move |spirit: &Arc<Spirit<S, O, C>>,
+                            socket: Arc<StdUdpSocket>,
+                            extra: (ExtraCfg, Buffers)| {
+            let (cfg, buffers) = extra;
+            trace!("Running UDP listener {} for {:?}", to_task_name, cfg);
+            let spirit = Arc::clone(spirit);
+            let action = Arc::clone(&action);
+            let label = to_task_name.to_string();
+            buffers
+                .apply(&*socket)
+                .and_then(|()| {
+                    // Another copy of the socket, std → tokio socket conversion
+                    let socket = socket.try_clone()?;
+                    Ok(UdpSocket::from_std(socket, &Handle::default())?)
+                })
+                .into_future()
+                .and_then(move |socket| action(&spirit, socket, &cfg))
+                .map_err(move |e| {
+                    metrics::report(|m| m.handler_error(&label));
+                    e
+                })
+        };
 
         let extract_name = name.clone();
         let extract = move |cfg: &C| {
             trace!("Extracting {}", extract_name);
-            extract(cfg).into_iter().map(|c| {
-                let (scale, results) = c.scale.scaled(&extract_name);
-                (c.listen, c.extra_cfg, scale, results)
+            let name = extract_name.clone();
+            extract(cfg).into_iter().flat_map(move |c| {
+                let (scale, results) = c.scale.scaled(&name);
+                expanded(&c.listen, c.extra_cfg, scale, results)
             })
         };
 
@@ -789,8 +1584,8 @@ pub type TokioBody = Box<Future<Item = (), Error = Error> + Send>;
 /// A helper to initialize a tokio runtime as part of spirit.
 ///
 /// The helpers in this crate ([`TcpListen`](struct.TcpListen.html),
-/// [`UdpListen`](struct.UdpListen.html)) use this to make sure they have a runtime to handle the
-/// sockets on.
+/// [`UdpListen`](struct.UdpListen.html), [`UnixListen`](struct.UnixListen.html)) use this to make
+/// sure they have a runtime to handle the sockets on.
 ///
 /// If you prefer to specify configuration of the runtime to use, instead of the default one, you
 /// can create an instance of this helper yourself and register it *before registering any socket
@@ -800,6 +1595,13 @@ pub type TokioBody = Box<Future<Item = (), Error = Error> + Send>;
 /// will be called just once, so you can use `Option<T>` inside and consume the value by
 /// `take.unwrap()`.
 ///
+/// # Shutdown
+///
+/// The runtime runs until it is empty. When the application terminates, all the resources of the
+/// [`Task`](struct.Task.html) based helpers are removed (listeners stop accepting and drain their
+/// connections) and registered as [pending](https://docs.rs/spirit/*/spirit/shutdown/index.html)
+/// until they finish, so a `shutdown-timeout-ms` can tell which ones are stuck.
+///
 /// # Future compatibility
 ///
 /// More options may be added into the enum at any time. Such change will not be considered a
@@ -870,3 +1672,185 @@ where
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::Runtime;
+    use tokio::timer::Interval;
+
+    use super::*;
+
+    #[test]
+    fn buffers_not_in_key() {
+        let small = Listen {
+            host: vec!["127.0.0.1".to_owned()],
+            recv_buf_size: Some(8192),
+            ..Listen::default()
+        };
+        let large = Listen {
+            recv_buf_size: Some(65536),
+            send_buf_size: Some(65536),
+            ..small.clone()
+        };
+        let small = expanded(&small, (), 1, ValidationResults::new());
+        let large = expanded(&large, (), 1, ValidationResults::new());
+        assert_eq!(small[0].0, large[0].0);
+        assert_eq!(None, small[0].0.recv_buf_size);
+        assert_ne!((small[0].1).1, (large[0].1).1);
+    }
+
+    #[test]
+    fn reuse_addr_defaults() {
+        let listen = Listen::default();
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let tcp = listen.bind_tcp(&addr).unwrap();
+        assert!(socket::getsockopt(tcp.as_raw_fd(), sockopt::ReuseAddr).unwrap());
+        let udp = listen.bind_udp(&addr).unwrap();
+        assert!(!socket::getsockopt(udp.as_raw_fd(), sockopt::ReuseAddr).unwrap());
+        let listen = Listen {
+            reuse_addr: Some(true),
+            ..listen
+        };
+        let udp = listen.bind_udp(&addr).unwrap();
+        assert!(socket::getsockopt(udp.as_raw_fd(), sockopt::ReuseAddr).unwrap());
+    }
+
+    #[test]
+    fn buffers_live() {
+        let socket = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        let get = || socket::getsockopt(socket.as_raw_fd(), sockopt::RcvBuf).unwrap();
+        let before = get();
+        let buffers = Buffers {
+            recv: Some(before * 2),
+            send: None,
+        };
+        buffers.apply(&socket).unwrap();
+        assert!(get() > before);
+    }
+
+    fn hosts(listens: &[Listen]) -> Vec<&str> {
+        listens
+            .iter()
+            .flat_map(|listen| listen.host.iter().map(|host| host.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn expand_hosts() {
+        let listen = Listen {
+            host: vec!["127.0.0.1".to_owned(), "localhost".to_owned()],
+            ..Listen::default()
+        };
+        let expanded = listen.expand().unwrap();
+        assert_eq!(vec!["127.0.0.1", "localhost"], hosts(&expanded));
+        assert!(expanded.iter().all(|e| e.port == listen.port));
+    }
+
+    #[test]
+    fn expand_all_addresses() {
+        let listen = Listen {
+            host: vec!["localhost".to_owned(), "127.0.0.2".to_owned()],
+            all_addresses: true,
+            ..Listen::default()
+        };
+        let expanded = listen.expand().unwrap();
+        let mut expected = ("localhost", 0)
+            .to_socket_addrs()
+            .unwrap()
+            .map(|addr| addr.ip().to_string())
+            .collect::<Vec<_>>();
+        expected.push("127.0.0.2".to_owned());
+        assert_eq!(expected, hosts(&expanded));
+        assert!(expanded.iter().all(|e| !e.all_addresses));
+        for listen in expanded {
+            let addresses = listen.addresses(0).unwrap();
+            assert_eq!(1, addresses.len());
+            assert!(addresses[0].ip().is_loopback());
+        }
+    }
+
+    #[test]
+    fn interface_addresses() {
+        let listen = Listen {
+            interface_addresses: Some("lo".to_owned()),
+            ..Listen::default()
+        };
+        let addresses = listen.addresses(1234).unwrap();
+        assert!(addresses.contains(&"127.0.0.1:1234".parse().unwrap()));
+        assert!(addresses.iter().all(|addr| addr.ip().is_loopback()));
+        let bound = listen.bind(0, |addr| listen.bind_tcp(addr)).unwrap();
+        assert!(bound.local_addr().unwrap().ip().is_loopback());
+    }
+
+    #[test]
+    fn expand_error() {
+        let listen = Listen {
+            interface_addresses: Some("nonexistent0".to_owned()),
+            all_addresses: true,
+            ..Listen::default()
+        };
+        let expanded = expanded(&listen, (), 1, ValidationResults::new());
+        assert_eq!(1, expanded.len());
+        assert_eq!(Some(ValidationLevel::Error), expanded[0].3.max_level());
+    }
+
+    /// Runs the future under the timeouts, returns how long it took to finish.
+    fn timed<F>(
+        conn: F,
+        activity: Arc<Mutex<Instant>>,
+        idle: Option<u64>,
+        lifetime: Option<u64>,
+    ) -> Duration
+    where
+        F: Future<Item = (), Error = Error> + Send + 'static,
+    {
+        let start = Instant::now();
+        let idle = idle.map(Duration::from_millis);
+        let lifetime = lifetime.map(Duration::from_millis);
+        let timeouts = Timeouts::new(conn, "test", activity, idle, lifetime);
+        Runtime::new().unwrap().block_on(timeouts).unwrap();
+        start.elapsed()
+    }
+
+    #[test]
+    fn max_lifetime() {
+        let activity = Arc::new(Mutex::new(Instant::now()));
+        let elapsed = timed(future::empty(), activity, None, Some(100));
+        let expected = Duration::from_millis(100);
+        assert!(elapsed >= expected, "Cut after {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "Cut after {:?}", elapsed);
+    }
+
+    #[test]
+    fn idle_despite_wakeups() {
+        let activity = Arc::new(Mutex::new(Instant::now()));
+        // Wakes up often, but never transfers anything
+        let conn = Interval::new_interval(Duration::from_millis(10))
+            .map_err(Error::from)
+            .for_each(|_| Ok(()));
+        let elapsed = timed(conn, activity, Some(100), None);
+        let expected = Duration::from_millis(100);
+        assert!(elapsed >= expected, "Cut after {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "Cut after {:?}", elapsed);
+    }
+
+    #[test]
+    fn idle_after_activity() {
+        let mut stream = Tracked::new(io::sink());
+        let activity = Arc::clone(&stream.activity);
+        // Writes for 300ms, then goes silent
+        let conn = Interval::new_interval(Duration::from_millis(30))
+            .take(10)
+            .map_err(Error::from)
+            .for_each(move |_| {
+                stream.write_all(b"x")?;
+                Ok(())
+            })
+            .and_then(|()| future::empty());
+        let elapsed = timed(conn, activity, Some(100), None);
+        // The last write plus the idle timeout
+        let expected = Duration::from_millis(400);
+        assert!(elapsed >= expected, "Cut after {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "Cut after {:?}", elapsed);
+    }
+}
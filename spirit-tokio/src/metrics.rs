@@ -0,0 +1,347 @@
+//! Metrics of the listening helpers.
+//!
+//! The helpers report what happens on their sockets to a [`Metrics`](trait.Metrics.html)
+//! implementation set by [`set_metrics`](fn.set_metrics.html). Each event is labelled by the `name`
+//! of the helper it comes from (the one passed to `config_helper` or the `helper` constructors),
+//! all the sockets and scaled instances of a helper are reported under the same name.
+//!
+//! There's a simple implementation counting the events in [`Counters`](struct.Counters.html),
+//! for anything else (sending them to a monitoring system, for example), implement the trait.
+//!
+//! Independently of that, the number of running [`Task`](../struct.Task.html) instances (listening
+//! sockets, connection pools, …) of each helper is tracked and available through
+//! [`running_tasks`](fn.running_tasks.html).
+//!
+//! Note that the handlers get the connections and sockets as they are, so the helpers don't see the
+//! transferred bytes. A handler interested in them can wrap its connection in
+//! [`Counted`](struct.Counted.html), which reports them under the same name. UDP sockets are not
+//! covered, their handlers need to report the bytes themselves.
+
+use std::collections::BTreeMap;
+use std::io::{Error as IoError, Read, Result as IoResult, Write};
+use std::sync::Arc;
+
+use parking_lot::{Mutex, RwLock};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::prelude::*;
+
+/// Receives the events of the helpers.
+///
+/// All the methods have empty default implementations, so an implementation can pick only the ones
+/// it is interested in.
+pub trait Metrics: Send + Sync {
+    /// A connection was accepted.
+    fn accepted(&self, _name: &str) {}
+    /// A connection was closed (for whatever reason).
+    ///
+    /// Together with [`accepted`](#method.accepted), this gives the number of active connections.
+    fn closed(&self, _name: &str) {}
+    /// A listener stopped accepting new connections because it reached its `max-conn` limit.
+    ///
+    /// It starts accepting again once some of the connections are closed. The clients waiting for
+    /// that time sit in the listen queue of the socket.
+    fn max_conn_reached(&self, _name: &str) {}
+    /// Accepting a connection failed.
+    ///
+    /// The listener waits for `error-sleep-ms` after each such error.
+    fn accept_error(&self, _name: &str) {}
+    /// A handler returned an error.
+    ///
+    /// For the connection based listeners, this is the handler of a single connection. For
+    /// `UdpListen`, it is the action handling the whole socket.
+    fn handler_error(&self, _name: &str) {}
+    /// Bytes were read from a connection wrapped in [`Counted`](struct.Counted.html).
+    fn received(&self, _name: &str, _bytes: u64) {}
+    /// Bytes were written to a connection wrapped in [`Counted`](struct.Counted.html).
+    fn sent(&self, _name: &str, _bytes: u64) {}
+}
+
+lazy_static! {
+    static ref METRICS: RwLock<Option<Arc<Metrics>>> = RwLock::new(None);
+    static ref RUNNING: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+}
+
+/// Sets where the helpers report their metrics.
+///
+/// This is global for the whole application and replaces the previous one, if any. It is
+/// expected to be called once, before the helpers start (events before that are not reported
+/// anywhere).
+pub fn set_metrics<M: Metrics + 'static>(metrics: M) {
+    *METRICS.write() = Some(Arc::new(metrics));
+}
+
+/// Reports an event to the current metrics, if any.
+pub(crate) fn report<F: FnOnce(&Metrics)>(event: F) {
+    if let Some(ref metrics) = *METRICS.read() {
+        event(&**metrics);
+    }
+}
+
+/// Returns the number of running task instances, for each helper name.
+///
+/// Helpers with no running instances are not included.
+pub fn running_tasks() -> BTreeMap<String, usize> {
+    RUNNING.lock().clone()
+}
+
+/// Marks one running task instance, until dropped.
+pub(crate) struct RunningTask(String);
+
+impl RunningTask {
+    pub(crate) fn new(name: String) -> Self {
+        *RUNNING.lock().entry(name.clone()).or_insert(0) += 1;
+        RunningTask(name)
+    }
+}
+
+impl Drop for RunningTask {
+    fn drop(&mut self) {
+        let mut running = RUNNING.lock();
+        let remove = {
+            let count = running
+                .get_mut(&self.0)
+                .expect("Running task not registered");
+            *count -= 1;
+            *count == 0
+        };
+        if remove {
+            running.remove(&self.0);
+        }
+    }
+}
+
+/// The counts of events of one helper, as collected by [`Counters`](struct.Counters.html).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// Number of accepted connections.
+    pub accepted: u64,
+    /// Number of connections being handled right now.
+    pub active: u64,
+    /// How many times a listener reached its `max-conn` limit.
+    pub max_conn_reached: u64,
+    /// Number of failed attempts to accept a connection.
+    pub accept_errors: u64,
+    /// Number of handlers that returned an error.
+    pub handler_errors: u64,
+    /// Bytes read from the [`Counted`](struct.Counted.html) connections.
+    pub bytes_received: u64,
+    /// Bytes written to the [`Counted`](struct.Counted.html) connections.
+    pub bytes_sent: u64,
+}
+
+/// A [`Metrics`](trait.Metrics.html) implementation counting the events in memory.
+///
+/// It can be shared through an `Arc`, one copy is set with
+/// [`set_metrics`](fn.set_metrics.html) and the other is used to read the current
+/// [`stats`](#method.stats).
+///
+/// # Examples
+///
+/// ```rust
+/// extern crate spirit_tokio;
+///
+/// use std::sync::Arc;
+///
+/// use spirit_tokio::metrics::{self, Counters};
+///
+/// fn main() {
+///     let counters = Arc::new(Counters::default());
+///     metrics::set_metrics(Arc::clone(&counters));
+///     // Register the helpers and run the application here
+///     for (name, stats) in counters.stats() {
+///         println!("{}: {} connections active", name, stats.active);
+///     }
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct Counters(Mutex<BTreeMap<String, Stats>>);
+
+impl Counters {
+    /// Returns a snapshot of the counts, for each helper name.
+    pub fn stats(&self) -> BTreeMap<String, Stats> {
+        self.0.lock().clone()
+    }
+
+    fn update<F: FnOnce(&mut Stats)>(&self, name: &str, update: F) {
+        let mut stats = self.0.lock();
+        if !stats.contains_key(name) {
+            stats.insert(name.to_owned(), Stats::default());
+        }
+        update(stats.get_mut(name).expect("Just inserted"));
+    }
+}
+
+impl Metrics for Counters {
+    fn accepted(&self, name: &str) {
+        self.update(name, |stats| {
+            stats.accepted += 1;
+            stats.active += 1;
+        });
+    }
+    fn closed(&self, name: &str) {
+        // A connection accepted before the counters were set may be closed after that.
+        self.update(name, |stats| stats.active = stats.active.saturating_sub(1));
+    }
+    fn max_conn_reached(&self, name: &str) {
+        self.update(name, |stats| stats.max_conn_reached += 1);
+    }
+    fn accept_error(&self, name: &str) {
+        self.update(name, |stats| stats.accept_errors += 1);
+    }
+    fn handler_error(&self, name: &str) {
+        self.update(name, |stats| stats.handler_errors += 1);
+    }
+    fn received(&self, name: &str, bytes: u64) {
+        self.update(name, |stats| stats.bytes_received += bytes);
+    }
+    fn sent(&self, name: &str, bytes: u64) {
+        self.update(name, |stats| stats.bytes_sent += bytes);
+    }
+}
+
+impl<M: Metrics + ?Sized> Metrics for Arc<M> {
+    fn accepted(&self, name: &str) {
+        (**self).accepted(name)
+    }
+    fn closed(&self, name: &str) {
+        (**self).closed(name)
+    }
+    fn max_conn_reached(&self, name: &str) {
+        (**self).max_conn_reached(name)
+    }
+    fn accept_error(&self, name: &str) {
+        (**self).accept_error(name)
+    }
+    fn handler_error(&self, name: &str) {
+        (**self).handler_error(name)
+    }
+    fn received(&self, name: &str, bytes: u64) {
+        (**self).received(name, bytes)
+    }
+    fn sent(&self, name: &str, bytes: u64) {
+        (**self).sent(name, bytes)
+    }
+}
+
+/// A connection reporting the bytes going through it.
+///
+/// The bytes are reported to the [`Metrics`](trait.Metrics.html) under the given name, usually the
+/// name of the helper the connection came from.
+///
+/// # Examples
+///
+/// ```rust
+/// # #![allow(dead_code)]
+/// extern crate failure;
+/// extern crate spirit;
+/// extern crate spirit_tokio;
+/// extern crate tokio;
+///
+/// use failure::Error;
+/// use spirit::{Empty, SpiritInner};
+/// use spirit_tokio::metrics::Counted;
+/// use tokio::net::TcpStream;
+/// use tokio::prelude::*;
+///
+/// fn handle_connection(
+///     _: &SpiritInner<Empty, Empty>,
+///     conn: TcpStream,
+///     _: &Empty,
+/// ) -> impl Future<Item = (), Error = Error> {
+///     let conn = Counted::new(conn, "listen");
+///     tokio::io::write_all(conn, b"Hello\n")
+///         .map(|_| ())
+///         .map_err(Error::from)
+/// }
+/// # fn main() {}
+/// ```
+#[derive(Debug)]
+pub struct Counted<S> {
+    inner: S,
+    name: String,
+}
+
+impl<S> Counted<S> {
+    /// Wraps the connection, reporting its bytes under the name.
+    pub fn new<N: Into<String>>(inner: S, name: N) -> Self {
+        Counted {
+            inner,
+            name: name.into(),
+        }
+    }
+
+    /// Returns the wrapped connection.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns the wrapped connection.
+    ///
+    /// The bytes transferred through it directly are not counted.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Unwraps the connection.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Read> Read for Counted<S> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let read = self.inner.read(buf)?;
+        if read > 0 {
+            report(|m| m.received(&self.name, read as u64));
+        }
+        Ok(read)
+    }
+}
+
+impl<S: Write> Write for Counted<S> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let written = self.inner.write(buf)?;
+        if written > 0 {
+            report(|m| m.sent(&self.name, written as u64));
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Counted<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for Counted<S> {
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        self.inner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters() {
+        let counters = Counters::default();
+        // Accepted before the counters were set up
+        counters.closed("listen");
+        counters.accepted("listen");
+        counters.received("listen", 10);
+        counters.sent("listen", 20);
+        counters.handler_error("other");
+        let stats = counters.stats();
+        let expected = Stats {
+            accepted: 1,
+            active: 1,
+            bytes_received: 10,
+            bytes_sent: 20,
+            ..Stats::default()
+        };
+        assert_eq!(expected, stats["listen"]);
+        assert_eq!(1, stats["other"].handler_errors);
+    }
+}
@@ -0,0 +1,493 @@
+//! A [Prometheus](https://prometheus.io) metrics endpoint.
+//!
+//! The [`helper`](fn.helper.html) serves the metrics in the Prometheus text format over HTTP, on
+//! `/metrics` of a [`TcpListen`](../struct.TcpListen.html) extracted from the configuration. If
+//! the listener should be part of some other helper, the [`serve`](fn.serve.html) connection
+//! handler can be used directly.
+//!
+//! It exports the state of spirit itself:
+//!
+//! * `spirit_config_reloads_total` and `spirit_config_reload_failures_total`: Number of
+//!   successful and failed configuration (re)loads.
+//! * `spirit_config_last_reload_timestamp_seconds` and
+//!   `spirit_config_last_reload_failure_timestamp_seconds`: When they happened the last time.
+//! * `spirit_config_validation_results_total`: Number of validation errors and warnings, labelled
+//!   by the `level`.
+//! * `spirit_running_tasks`: Number of running [`Task`](../struct.Task.html) instances, labelled
+//!   by the `name` of the helper.
+//! * `spirit_log_messages_total`: Number of log messages, labelled by the `level`.
+//!
+//! The application can add its own metrics by [`register`](fn.register.html)ing a collector
+//! (either a closure writing into the [`Output`](struct.Output.html), or a prepared
+//! [`Counter`](struct.Counter.html) or [`Gauge`](struct.Gauge.html)). The
+//! [`Counters`](../metrics/struct.Counters.html) of the listening helpers can be registered too.
+//!
+//! The HTTP support is minimal ‒ it reads the request head, answers with the metrics (or 404 on
+//! other paths) and closes the connection. The listener isn't meant to face the outside world.
+//!
+//! # Examples
+//!
+//! ```rust
+//! extern crate serde;
+//! #[macro_use]
+//! extern crate serde_derive;
+//! extern crate spirit;
+//! extern crate spirit_tokio;
+//!
+//! use spirit::{Empty, Spirit};
+//! use spirit_tokio::prometheus::{self, Counter};
+//! use spirit_tokio::TcpListen;
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [metrics]
+//! host = "127.0.0.1"
+//! port = 9898
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Config {
+//!     metrics: TcpListen,
+//! }
+//!
+//! impl Config {
+//!     fn metrics(&self) -> Vec<TcpListen> {
+//!         vec![self.metrics.clone()]
+//!     }
+//! }
+//!
+//! fn main() {
+//!     let jobs = Counter::register("app_jobs_total", "Number of jobs done");
+//!     Spirit::<_, Empty, _>::new(Config::default())
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(prometheus::helper(Config::metrics, "metrics"))
+//!         .run(move |spirit| {
+//!             jobs.inc();
+//! #           spirit.terminate();
+//!             Ok(())
+//!         });
+//! }
+//! ```
+
+use std::borrow::Borrow;
+use std::fmt::{Debug, Display, Write};
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use failure::Error;
+use parking_lot::RwLock;
+use serde::Deserialize;
+use spirit::helpers::Helper;
+use spirit::stats::Stats as SpiritStats;
+use spirit::{ArcSwap, Empty, Spirit};
+use structopt::StructOpt;
+use tokio::codec::{FramedRead, LinesCodec};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+
+use metrics::{self, Counters};
+use TcpListen;
+use Tracked;
+
+const MAX_LINE: usize = 8192;
+const MAX_LINES: u64 = 100;
+
+/// A type of a metric family.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Kind {
+    /// A value that only ever grows.
+    Counter,
+    /// A value that can go up and down.
+    Gauge,
+}
+
+/// The metrics being produced, in the Prometheus text format.
+///
+/// A collector first announces a metric family with [`family`](#method.family) and then writes
+/// its samples with [`sample`](#method.sample).
+#[derive(Debug, Default)]
+pub struct Output(String);
+
+impl Output {
+    /// Starts a metric family, with its help text and type.
+    pub fn family(&mut self, name: &str, help: &str, kind: Kind) {
+        let help = help.replace('\\', "\\\\").replace('\n', "\\n");
+        let kind = match kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+        };
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+    }
+
+    /// Writes one sample, with the given labels.
+    pub fn sample<V: Display>(&mut self, name: &str, labels: &[(&str, &str)], value: V) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            let labels = labels
+                .iter()
+                .map(|(label, value)| {
+                    let value = value
+                        .replace('\\', "\\\\")
+                        .replace('"', "\\\"")
+                        .replace('\n', "\\n");
+                    format!("{}=\"{}\"", label, value)
+                })
+                .collect::<Vec<_>>()
+                .join(",");
+            let _ = write!(self.0, "{{{}}}", labels);
+        }
+        let _ = writeln!(self.0, " {}", value);
+    }
+}
+
+/// Something that produces metrics.
+///
+/// Implemented for closures taking the [`Output`](struct.Output.html).
+pub trait Collect: Send + Sync {
+    /// Writes the current values of the metrics.
+    fn collect(&self, output: &mut Output);
+}
+
+impl<F: Fn(&mut Output) + Send + Sync> Collect for F {
+    fn collect(&self, output: &mut Output) {
+        self(output)
+    }
+}
+
+impl<C: Collect + ?Sized> Collect for Arc<C> {
+    fn collect(&self, output: &mut Output) {
+        (**self).collect(output)
+    }
+}
+
+impl Collect for Counters {
+    fn collect(&self, output: &mut Output) {
+        let stats = self.stats();
+        let mut family = |name: &str, help: &str, kind: Kind, get: fn(&metrics::Stats) -> u64| {
+            output.family(name, help, kind);
+            for (helper, stats) in &stats {
+                output.sample(name, &[("name", helper)], get(stats));
+            }
+        };
+        family(
+            "spirit_connections_accepted_total",
+            "Number of accepted connections",
+            Kind::Counter,
+            |s| s.accepted,
+        );
+        family(
+            "spirit_connections_active",
+            "Number of connections being handled",
+            Kind::Gauge,
+            |s| s.active,
+        );
+        family(
+            "spirit_max_conn_reached_total",
+            "How many times a listener reached its max-conn limit",
+            Kind::Counter,
+            |s| s.max_conn_reached,
+        );
+        family(
+            "spirit_accept_errors_total",
+            "Number of failed attempts to accept a connection",
+            Kind::Counter,
+            |s| s.accept_errors,
+        );
+        family(
+            "spirit_handler_errors_total",
+            "Number of handlers that returned an error",
+            Kind::Counter,
+            |s| s.handler_errors,
+        );
+        family(
+            "spirit_bytes_received_total",
+            "Bytes read from the counted connections",
+            Kind::Counter,
+            |s| s.bytes_received,
+        );
+        family(
+            "spirit_bytes_sent_total",
+            "Bytes written to the counted connections",
+            Kind::Counter,
+            |s| s.bytes_sent,
+        );
+    }
+}
+
+lazy_static! {
+    static ref COLLECTORS: RwLock<Vec<Box<Collect>>> = RwLock::new(Vec::new());
+}
+
+/// Adds a collector of application metrics to the endpoint.
+///
+/// The collectors are global for the whole application and stay registered forever. They are
+/// called in the order of registration on each request.
+pub fn register<C: Collect + 'static>(collector: C) {
+    COLLECTORS.write().push(Box::new(collector));
+}
+
+/// A registered counter.
+///
+/// It's a handle that can be cloned and shared between threads.
+#[derive(Clone, Debug)]
+pub struct Counter(Arc<AtomicUsize>);
+
+impl Counter {
+    /// Creates a new counter and [`register`](fn.register.html)s it.
+    pub fn register(name: &'static str, help: &'static str) -> Self {
+        let value = Arc::new(AtomicUsize::new(0));
+        let collected = Arc::clone(&value);
+        register(move |output: &mut Output| {
+            output.family(name, help, Kind::Counter);
+            output.sample(name, &[], collected.load(Ordering::Relaxed));
+        });
+        Counter(value)
+    }
+
+    /// Increments the counter by one.
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    /// Increments the counter by the given amount.
+    pub fn add(&self, amount: usize) {
+        self.0.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    /// Returns the current value.
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A registered gauge.
+///
+/// It's a handle that can be cloned and shared between threads.
+#[derive(Clone, Debug)]
+pub struct Gauge(Arc<AtomicIsize>);
+
+impl Gauge {
+    /// Creates a new gauge and [`register`](fn.register.html)s it.
+    pub fn register(name: &'static str, help: &'static str) -> Self {
+        let value = Arc::new(AtomicIsize::new(0));
+        let collected = Arc::clone(&value);
+        register(move |output: &mut Output| {
+            output.family(name, help, Kind::Gauge);
+            output.sample(name, &[], collected.load(Ordering::Relaxed));
+        });
+        Gauge(value)
+    }
+
+    /// Sets the gauge to the given value.
+    pub fn set(&self, value: isize) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    /// Increments the gauge by one.
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Decrements the gauge by one.
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Returns the current value.
+    pub fn get(&self) -> isize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+fn timestamp(time: SystemTime) -> f64 {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since.as_secs() as f64 + f64::from(since.subsec_nanos()) / 1_000_000_000.0
+}
+
+fn render(stats: &SpiritStats) -> String {
+    let mut output = Output::default();
+    let name = "spirit_config_reloads_total";
+    output.family(
+        name,
+        "Number of successful configuration (re)loads",
+        Kind::Counter,
+    );
+    output.sample(name, &[], stats.reloads);
+    let name = "spirit_config_reload_failures_total";
+    output.family(
+        name,
+        "Number of failed configuration reloads",
+        Kind::Counter,
+    );
+    output.sample(name, &[], stats.reload_failures);
+    if let Some(last) = stats.last_reload {
+        let name = "spirit_config_last_reload_timestamp_seconds";
+        let help = "When the configuration was successfully (re)loaded the last time";
+        output.family(name, help, Kind::Gauge);
+        output.sample(name, &[], timestamp(last));
+    }
+    if let Some(last) = stats.last_reload_failure {
+        let name = "spirit_config_last_reload_failure_timestamp_seconds";
+        let help = "When a configuration reload failed the last time";
+        output.family(name, help, Kind::Gauge);
+        output.sample(name, &[], timestamp(last));
+    }
+    let name = "spirit_config_validation_results_total";
+    output.family(name, "Number of validation results", Kind::Counter);
+    output.sample(name, &[("level", "error")], stats.validation_errors);
+    output.sample(name, &[("level", "warning")], stats.validation_warnings);
+    let name = "spirit_running_tasks";
+    output.family(name, "Number of running task instances", Kind::Gauge);
+    for (task, count) in metrics::running_tasks() {
+        output.sample(name, &[("name", &task)], count);
+    }
+    let name = "spirit_log_messages_total";
+    output.family(name, "Number of log messages", Kind::Counter);
+    for &(level, count) in &stats.log_messages {
+        let level = level.to_string().to_lowercase();
+        output.sample(name, &[("level", &level)], count);
+    }
+    for collector in COLLECTORS.read().iter() {
+        collector.collect(&mut output);
+    }
+    output.0
+}
+
+fn response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body,
+    )
+}
+
+/// Reads the request from the connection and answers it.
+fn respond(
+    stats: SpiritStats,
+    conn: Tracked<TcpStream>,
+) -> impl Future<Item = (), Error = Error> + Send {
+    let (read, write) = conn.split();
+    FramedRead::new(read, LinesCodec::new_with_max_length(MAX_LINE))
+        .take(MAX_LINES)
+        .take_while(|line| Ok(!line.is_empty()))
+        .collect()
+        .map_err(Error::from)
+        .and_then(move |head| {
+            let mut request = head
+                .first()
+                .map(String::as_str)
+                .unwrap_or("")
+                .split_whitespace();
+            let method = request.next();
+            let path = request.next().map(|p| p.split('?').next().unwrap_or(p));
+            let response = match (method, path) {
+                (Some("GET"), Some("/metrics")) => {
+                    response("200 OK", "text/plain; version=0.0.4", &render(&stats))
+                }
+                (Some("GET"), _) => response("404 Not Found", "text/plain", "Not found\n"),
+                _ => response(
+                    "405 Method Not Allowed",
+                    "text/plain",
+                    "Method not allowed\n",
+                ),
+            };
+            tokio::io::write_all(write, response)
+                .and_then(|(write, _)| tokio::io::shutdown(write))
+                .map(|_| ())
+                .map_err(Error::from)
+        })
+}
+
+/// Handles one HTTP connection to the metrics endpoint.
+///
+/// This can be used as the action of a [`TcpListen`](../struct.TcpListen.html). A `GET` of
+/// `/metrics` is answered with the metrics, any other request with an error status.
+pub fn serve<S, O, C, ExtraCfg>(
+    spirit: &Arc<Spirit<S, O, C>>,
+    conn: Tracked<TcpStream>,
+    _: &ExtraCfg,
+) -> impl Future<Item = (), Error = Error> + Send
+where
+    S: Borrow<ArcSwap<C>> + Send + Sync + 'static,
+    for<'de> C: Deserialize<'de> + Send + Sync,
+    O: StructOpt,
+{
+    respond(spirit.stats(), conn)
+}
+
+/// A helper serving the metrics on the extracted listeners.
+///
+/// # Parameters
+///
+/// * `extract`: Closure that extracts an iterator of `TcpListen` out of the whole configuration.
+/// * `name`: How to call the instances in logs.
+pub fn helper<Extract, ExtractIt, Name, S, O, C>(
+    extract: Extract,
+    name: Name,
+) -> impl Helper<S, O, C>
+where
+    S: Borrow<ArcSwap<C>> + Sync + Send + 'static,
+    for<'de> C: Deserialize<'de> + Send + Sync + 'static,
+    O: Debug + StructOpt + Sync + Send + 'static,
+    Extract: FnMut(&C) -> ExtractIt + Send + 'static,
+    ExtractIt: IntoIterator<Item = TcpListen>,
+    Name: Clone + Display + Send + Sync + 'static,
+{
+    TcpListen::helper(extract, serve::<S, O, C, Empty>, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener as StdTcpListener, TcpStream as StdTcpStream};
+    use std::thread;
+
+    use tokio::reactor::Handle;
+    use tokio::runtime::Runtime;
+
+    use super::*;
+
+    fn request(request: &'static str) -> String {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut conn = StdTcpStream::connect(addr).unwrap();
+            conn.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            conn.read_to_string(&mut response).unwrap();
+            response
+        });
+        let (conn, _) = listener.accept().unwrap();
+        let conn = TcpStream::from_std(conn, &Handle::default()).unwrap();
+        let conn = Tracked::new(conn);
+        let mut runtime = Runtime::new().unwrap();
+        runtime
+            .block_on(respond(SpiritStats::default(), conn))
+            .unwrap();
+        client.join().unwrap()
+    }
+
+    #[test]
+    fn metrics() {
+        let counter = Counter::register("test_requests_total", "Number of test requests");
+        counter.add(3);
+        let response = request("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+        assert!(body.contains("# TYPE spirit_config_reloads_total counter\n"));
+        assert!(body.contains("# TYPE spirit_running_tasks gauge\n"));
+        assert!(body.contains("# HELP test_requests_total Number of test requests\n"));
+        assert!(body.contains("# TYPE test_requests_total counter\ntest_requests_total 3\n"));
+    }
+
+    #[test]
+    fn not_found() {
+        let response = request("GET /other HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = request("POST /metrics HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+}
@@ -0,0 +1,525 @@
+//! TLS on top of the TCP listeners.
+
+use std::borrow::Borrow;
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::fs;
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write};
+use std::iter;
+use std::mem;
+use std::net::TcpListener as StdTcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use failure::Error;
+use openssl::pkey::PKey;
+use openssl::ssl::{
+    ErrorCode, HandshakeError, MidHandshakeSslStream, SslAcceptor, SslMethod, SslStream,
+    SslVerifyMode, SslVersion,
+};
+use openssl::x509::X509;
+use serde::Deserialize;
+use spirit::helpers::{CfgHelper, Helper, IteratedCfgHelper};
+use spirit::validation::Result as ValidationResult;
+use spirit::{ArcSwap, Builder, Empty, Spirit};
+use structopt::StructOpt;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::*;
+use tokio::reactor::Handle;
+
+use super::{
+    accept_loop, expanded, Accept, Buffers, Listen, Scale, Scaled, StreamOpts, Task, TcpListen,
+    Tracked,
+};
+
+/// An error returned when the certificate chain file contains no certificate.
+#[derive(Debug, Fail)]
+#[fail(display = "No certificate in {}", _0)]
+pub struct NoCertificate(String);
+
+/// A TLS protocol version, as written in the configuration.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum TlsVersion {
+    /// TLS 1.0.
+    #[serde(rename = "tls1")]
+    Tls1,
+    /// TLS 1.1.
+    #[serde(rename = "tls1.1")]
+    Tls11,
+    /// TLS 1.2.
+    #[serde(rename = "tls1.2")]
+    Tls12,
+    /// TLS 1.3.
+    #[serde(rename = "tls1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    fn ssl_version(self) -> SslVersion {
+        match self {
+            TlsVersion::Tls1 => SslVersion::TLS1,
+            TlsVersion::Tls11 => SslVersion::TLS1_1,
+            TlsVersion::Tls12 => SslVersion::TLS1_2,
+            TlsVersion::Tls13 => SslVersion::TLS1_3,
+        }
+    }
+}
+
+/// The TLS part of the [`TlsListen`](struct.TlsListen.html) configuration.
+///
+/// It contains these configuration options:
+///
+/// * `cert-chain` (mandatory): A PEM file with the certificate, followed by the intermediate
+///   certificates.
+/// * `private-key` (mandatory): A PEM file with the private key of the certificate.
+/// * `client-ca` (optional): A PEM file with the certificate authorities to check client
+///   certificates against. If set, clients without a valid certificate are refused.
+/// * `min-protocol` and `max-protocol` (optional): The range of allowed protocol versions, one of
+///   `tls1`, `tls1.1`, `tls1.2` and `tls1.3`. Defaults to what the library considers reasonable.
+/// * `ciphers` (optional): The allowed cipher suites up to TLS 1.2, in the OpenSSL format.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct TlsCfg {
+    #[serde(rename = "cert-chain")]
+    cert_chain: PathBuf,
+    #[serde(rename = "private-key")]
+    private_key: PathBuf,
+    #[serde(rename = "client-ca")]
+    client_ca: Option<PathBuf>,
+    #[serde(rename = "min-protocol")]
+    min_protocol: Option<TlsVersion>,
+    #[serde(rename = "max-protocol")]
+    max_protocol: Option<TlsVersion>,
+    ciphers: Option<String>,
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, Error> {
+    fs::read(path).map_err(|e| format_err!("Can't read {}: {}", path.display(), e))
+}
+
+/// The content of the files, loaded anew on each configuration reload.
+#[derive(Clone, PartialEq)]
+struct Material {
+    cfg: TlsCfg,
+    cert_chain: Vec<u8>,
+    private_key: Vec<u8>,
+    client_ca: Option<Vec<u8>>,
+}
+
+impl Material {
+    fn load(cfg: &TlsCfg) -> Result<Self, Error> {
+        Ok(Material {
+            cfg: cfg.clone(),
+            cert_chain: read(&cfg.cert_chain)?,
+            private_key: read(&cfg.private_key)?,
+            client_ca: match cfg.client_ca {
+                Some(ref path) => Some(read(path)?),
+                None => None,
+            },
+        })
+    }
+
+    fn acceptor(&self) -> Result<SslAcceptor, Error> {
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+        let mut chain = X509::stack_from_pem(&self.cert_chain)?.into_iter();
+        let cert = chain
+            .next()
+            .ok_or_else(|| NoCertificate(self.cfg.cert_chain.display().to_string()))?;
+        builder.set_certificate(&cert)?;
+        for intermediate in chain {
+            builder.add_extra_chain_cert(intermediate)?;
+        }
+        let key = PKey::private_key_from_pem(&self.private_key)?;
+        builder.set_private_key(&key)?;
+        builder.check_private_key()?;
+        if let Some(ref client_ca) = self.client_ca {
+            for ca in X509::stack_from_pem(client_ca)? {
+                builder.add_client_ca(&ca)?;
+                builder.cert_store_mut().add_cert(ca)?;
+            }
+            builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        }
+        builder.set_min_proto_version(self.cfg.min_protocol.map(TlsVersion::ssl_version))?;
+        builder.set_max_proto_version(self.cfg.max_protocol.map(TlsVersion::ssl_version))?;
+        if let Some(ref ciphers) = self.cfg.ciphers {
+            builder.set_cipher_list(ciphers)?;
+        }
+        Ok(builder.build())
+    }
+}
+
+/// The acceptor made of the loaded material.
+///
+/// It compares by the material, so a changed certificate causes the listener to be replaced (the
+/// socket stays, only the acceptor is new).
+#[derive(Clone)]
+struct Acceptor {
+    material: Option<Material>,
+    acceptor: Option<Arc<SslAcceptor>>,
+}
+
+impl Acceptor {
+    fn new(cfg: &TlsCfg) -> Result<Self, Error> {
+        let material = Material::load(cfg)?;
+        let acceptor = material.acceptor()?;
+        Ok(Acceptor {
+            material: Some(material),
+            acceptor: Some(Arc::new(acceptor)),
+        })
+    }
+}
+
+impl PartialEq for Acceptor {
+    fn eq(&self, other: &Acceptor) -> bool {
+        self.material == other.material
+    }
+}
+
+// Manual, to keep the private key out of the logs
+impl Debug for Acceptor {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("Acceptor")
+            .field("cfg", &self.material.as_ref().map(|m| &m.cfg))
+            .finish()
+    }
+}
+
+/// A TLS connection, after the handshake.
+///
+/// It works as a `Read`/`Write` as well as `AsyncRead`/`AsyncWrite`, the encryption is done
+/// transparently.
+#[derive(Debug)]
+pub struct TlsStream<S>(SslStream<S>);
+
+impl<S> TlsStream<S> {
+    /// Access to the underlying OpenSSL stream, for example to check the client certificate.
+    pub fn get_ref(&self) -> &SslStream<S> {
+        &self.0
+    }
+
+    /// Mutable access to the underlying OpenSSL stream.
+    pub fn get_mut(&mut self) -> &mut SslStream<S> {
+        &mut self.0
+    }
+}
+
+impl<S: Read + Write> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<S: Read + Write> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.0.write(buf)
+    }
+    fn flush(&mut self) -> IoResult<()> {
+        self.0.flush()
+    }
+}
+
+// The OpenSSL stream passes the WouldBlock of the underlying stream through, which is all tokio
+// needs.
+impl<S: AsyncRead + AsyncWrite> AsyncRead for TlsStream<S> {}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for TlsStream<S> {
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        match self.0.shutdown() {
+            Ok(_) => (),
+            Err(ref e) if e.code() == ErrorCode::ZERO_RETURN => (),
+            Err(e) => match e.into_io_error() {
+                Ok(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Ok(e) => return Err(e),
+                Err(e) => return Err(IoError::other(e)),
+            },
+        }
+        self.0.get_mut().shutdown()
+    }
+}
+
+/// The TLS handshake of a freshly accepted connection.
+enum Handshake<S> {
+    Start(Arc<SslAcceptor>, S),
+    Mid(MidHandshakeSslStream<S>),
+    Done,
+}
+
+impl<S: Debug + Read + Write> Future for Handshake<S> {
+    type Item = TlsStream<S>;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<TlsStream<S>, Error> {
+        let result = match mem::replace(self, Handshake::Done) {
+            Handshake::Start(acceptor, stream) => acceptor.accept(stream),
+            Handshake::Mid(mid) => mid.handshake(),
+            Handshake::Done => panic!("Handshake polled after completion"),
+        };
+        match result {
+            Ok(stream) => Ok(Async::Ready(TlsStream(stream))),
+            Err(HandshakeError::WouldBlock(mid)) => {
+                *self = Handshake::Mid(mid);
+                Ok(Async::NotReady)
+            }
+            Err(HandshakeError::Failure(mid)) => Err(mid.into_error().into()),
+            Err(HandshakeError::SetupFailure(e)) => Err(e.into()),
+        }
+    }
+}
+
+/// The parts of the configuration passed to the installed listener.
+#[derive(Clone, Debug, PartialEq)]
+struct Extra<ExtraCfg> {
+    cfg: ExtraCfg,
+    accept: Accept,
+    stream_opts: StreamOpts,
+    acceptor: Acceptor,
+}
+
+/// A configuration fragment of a TLS listening socket.
+///
+/// This is a [`TcpListen`](struct.TcpListen.html) with a TLS layer on top. The action callback
+/// gets each connection after a successful TLS handshake (failed handshakes are logged).
+///
+/// The certificate, key and client CA files are read anew on each configuration reload. If any
+/// of them changed, the listener is replaced by one with the new certificates, but the socket
+/// itself stays open. The connections already accepted keep the old certificates.
+///
+/// It is available with the `tls` feature.
+///
+/// # Type parameters
+///
+/// The same as with `TcpListen`.
+///
+/// # Configuration options
+///
+/// All the options of `TcpListen`, together with the options described in
+/// [`TlsCfg`](struct.TlsCfg.html).
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// extern crate failure;
+/// #[macro_use]
+/// extern crate serde_derive;
+/// extern crate spirit;
+/// extern crate spirit_tokio;
+/// extern crate tokio;
+///
+/// use failure::Error;
+/// use spirit::{Empty, Spirit, SpiritInner};
+/// use spirit_tokio::{TlsListen, TlsStream, Tracked};
+/// use tokio::net::TcpStream;
+/// use tokio::prelude::*;
+///
+/// const DEFAULT_CONFIG: &str = r#"
+/// [listen]
+/// port = 8443
+/// cert-chain = "/etc/example/chain.pem"
+/// private-key = "/etc/example/key.pem"
+/// min-protocol = "tls1.2"
+/// "#;
+///
+/// #[derive(Default, Deserialize)]
+/// struct Config {
+///     listen: TlsListen,
+/// }
+///
+/// fn connection(
+///     _: &SpiritInner<Empty, Config>,
+///     conn: TlsStream<Tracked<TcpStream>>,
+///     _: &Empty,
+/// ) -> impl Future<Item = (), Error = Error> {
+///     tokio::io::write_all(conn, "Hello\n")
+///         .map(|_| ())
+///         .map_err(Error::from)
+/// }
+///
+/// fn main() {
+///     Spirit::<_, Empty, _>::new(Config::default())
+///         .config_defaults(DEFAULT_CONFIG)
+///         .config_helper(|cfg: &Config| cfg.listen.clone(), connection, "listen")
+///         .run(|_| Ok(()));
+/// }
+/// ```
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct TlsListen<ExtraCfg = Empty, ScaleMode: Scaled = Scale> {
+    // The TLS options go first, so the unused keys of the TcpListen don't see them.
+    #[serde(flatten)]
+    tls: TlsCfg,
+    #[serde(flatten)]
+    tcp: TcpListen<ExtraCfg, ScaleMode>,
+}
+
+impl<ExtraCfg: Default, ScaleMode: Default + Scaled> Default for TlsListen<ExtraCfg, ScaleMode> {
+    fn default() -> Self {
+        Self {
+            tls: TlsCfg::default(),
+            tcp: TcpListen::default(),
+        }
+    }
+}
+
+impl<ExtraCfg: Clone + Debug + PartialEq + Send + 'static> TlsListen<ExtraCfg> {
+    /// Provides a helper for this configuration.
+    ///
+    /// While you are free to use this directly, it is more commonly used through
+    /// `spirit::Builder::config_helper` with an extractor returning iterator of this type.
+    ///
+    /// # Parameters
+    ///
+    /// * `extract`: Closure that extracts an iterator of `TlsListen` out of the whole
+    ///   configuration.
+    /// * `conn`: An action to be taken on each accepted connection, after the handshake.
+    /// * `name`: How to call the instances in logs.
+    pub fn helper<Extract, ExtractIt, Conn, ConnFut, Name, S, O, C>(
+        mut extract: Extract,
+        conn: Conn,
+        name: Name,
+    ) -> impl Helper<S, O, C>
+    where
+        S: Borrow<ArcSwap<C>> + Sync + Send + 'static,
+        for<'de> C: Deserialize<'de> + Send + Sync + 'static,
+        O: Debug + StructOpt + Sync + Send + 'static,
+        Extract: FnMut(&C) -> ExtractIt + Send + 'static,
+        ExtractIt: IntoIterator<Item = Self>,
+        Conn: Fn(&Arc<Spirit<S, O, C>>, TlsStream<Tracked<TcpStream>>, &ExtraCfg) -> ConnFut
+            + Sync
+            + Send
+            + 'static,
+        ConnFut: Future<Item = (), Error = Error> + Send + 'static,
+        Name: Clone + Display + Send + Sync + 'static,
+    {
+        let conn = Arc::new(conn);
+
+        let to_task_name = name.clone();
+        let to_task = move |spirit: &Arc<Spirit<S, O, C>>,
+                            listener: Arc<StdTcpListener>,
+                            extra: (Extra<ExtraCfg>, Buffers)| {
+            let spirit = Arc::clone(spirit);
+            let conn = Arc::clone(&conn);
+            let name = to_task_name.clone();
+            let pending = spirit.pending(format!("connections on {}", name));
+            let (extra, buffers) = extra;
+            let Extra {
+                cfg,
+                accept,
+                stream_opts,
+                acceptor,
+            } = extra;
+            acceptor
+                .acceptor
+                .ok_or_else(|| format_err!("No TLS acceptor for {}", name))
+                .and_then(|acceptor| {
+                    buffers.apply(&*listener)?;
+                    // Another copy of the listener, std → tokio socket conversion
+                    let listener = listener.try_clone()?;
+                    let listener = TcpListener::from_std(listener, &Handle::default())?;
+                    Ok((acceptor, listener))
+                })
+                .into_future()
+                .and_then(move |(acceptor, listener)| {
+                    let conn_name = name.clone();
+                    let handle = move |new_conn: Tracked<TcpStream>| {
+                        stream_opts.apply(new_conn.get_ref(), &conn_name);
+                        let spirit = Arc::clone(&spirit);
+                        let conn = Arc::clone(&conn);
+                        let cfg = cfg.clone();
+                        Handshake::Start(Arc::clone(&acceptor), new_conn)
+                            .and_then(move |tls| conn(&spirit, tls, &cfg))
+                    };
+                    accept_loop(listener.incoming(), accept, name, pending, handle)
+                })
+        };
+
+        let extract_name = name.clone();
+        let extract = move |cfg: &C| {
+            let name = extract_name.clone();
+            extract(cfg).into_iter().flat_map(move |c| {
+                let TlsListen { tls, tcp } = c;
+                let (scale, mut results) = tcp.scale.scaled(&name);
+                // Invalid material refuses the whole configuration, so this never gets used.
+                let acceptor = Acceptor::new(&tls).unwrap_or_else(|e| {
+                    results.merge(ValidationResult::error(format!("{}: {}", name, e)));
+                    Acceptor {
+                        material: None,
+                        acceptor: None,
+                    }
+                });
+                let stream_opts = tcp.stream_opts();
+                let extra = Extra {
+                    cfg: tcp.extra_cfg,
+                    accept: Accept {
+                        error_sleep: Duration::from_millis(tcp.error_sleep_ms),
+                        max_conn: tcp.max_conn,
+                        drain_timeout: tcp.drain_timeout_ms.map(Duration::from_millis),
+                        idle_timeout: tcp.idle_timeout_ms.map(Duration::from_millis),
+                        max_lifetime: tcp.max_conn_lifetime_ms.map(Duration::from_millis),
+                    },
+                    stream_opts,
+                    acceptor,
+                };
+                expanded(&tcp.listen, extra, scale, results)
+            })
+        };
+
+        Task {
+            extract,
+            build: Listen::create_tcp,
+            to_task,
+            name,
+        }
+    }
+}
+
+impl<S, O, C, Conn, ConnFut, ExtraCfg> IteratedCfgHelper<S, O, C, Conn> for TlsListen<ExtraCfg>
+where
+    S: Borrow<ArcSwap<C>> + Sync + Send + 'static,
+    for<'de> C: Deserialize<'de> + Send + Sync + 'static,
+    O: Debug + StructOpt + Sync + Send + 'static,
+    ExtraCfg: Clone + Debug + PartialEq + Send + 'static,
+    Conn: Fn(&Arc<Spirit<S, O, C>>, TlsStream<Tracked<TcpStream>>, &ExtraCfg) -> ConnFut
+        + Sync
+        + Send
+        + 'static,
+    ConnFut: Future<Item = (), Error = Error> + Send + 'static,
+{
+    fn apply<Extractor, ExtractedIter, Name>(
+        extractor: Extractor,
+        action: Conn,
+        name: Name,
+        builder: Builder<S, O, C>,
+    ) -> Builder<S, O, C>
+    where
+        Extractor: FnMut(&C) -> ExtractedIter + Send + 'static,
+        ExtractedIter: IntoIterator<Item = Self>,
+        Name: Clone + Display + Send + Sync + 'static,
+    {
+        Self::helper(extractor, action, name).apply(builder)
+    }
+}
+
+impl<S, O, C, Conn, ConnFut, ExtraCfg> CfgHelper<S, O, C, Conn> for TlsListen<ExtraCfg>
+where
+    S: Borrow<ArcSwap<C>> + Sync + Send + 'static,
+    for<'de> C: Deserialize<'de> + Send + Sync + 'static,
+    O: Debug + StructOpt + Sync + Send + 'static,
+    ExtraCfg: Clone + Debug + PartialEq + Send + 'static,
+    Conn: Fn(&Arc<Spirit<S, O, C>>, TlsStream<Tracked<TcpStream>>, &ExtraCfg) -> ConnFut
+        + Sync
+        + Send
+        + 'static,
+    ConnFut: Future<Item = (), Error = Error> + Send + 'static,
+{
+    fn apply<Extractor, Name>(
+        mut extractor: Extractor,
+        action: Conn,
+        name: Name,
+        builder: Builder<S, O, C>,
+    ) -> Builder<S, O, C>
+    where
+        Extractor: FnMut(&C) -> Self + Send + 'static,
+        Name: Clone + Display + Send + Sync + 'static,
+    {
+        let extractor = move |cfg: &_| iter::once(extractor(cfg));
+        Self::helper(extractor, action, name).apply(builder)
+    }
+}
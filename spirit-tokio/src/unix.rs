@@ -0,0 +1,518 @@
+//! Listening on unix domain sockets.
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt::{Debug, Display};
+use std::fs::{self, DirBuilder, Permissions};
+use std::io::ErrorKind;
+use std::iter;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener as StdUnixListener, UnixStream as StdUnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use failure::Error;
+use nix::unistd::{self, Gid, Uid};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use spirit::helpers::{CfgHelper, Helper, IteratedCfgHelper};
+use spirit::validation::{Result as ValidationResult, Unused};
+use spirit::{ArcSwap, Builder, Empty, SecId, Spirit};
+use structopt::StructOpt;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::prelude::*;
+use tokio::reactor::Handle;
+
+use upgrade;
+
+use super::{
+    accept_loop, default_error_sleep, default_max_conn, Accept, Scale, Scaled, Task, Tracked,
+};
+
+lazy_static! {
+    /// The paths we've bound a socket to.
+    ///
+    /// If the configuration of a socket changes, but the path stays the same, the listener still
+    /// alive is reused (binding a new one would fail). The dead ones are left behind by us, so
+    /// they can be removed.
+    static ref BOUND: Mutex<HashMap<PathBuf, Weak<StdUnixListener>>> = Mutex::new(HashMap::new());
+}
+
+/// An error returned when the permissions of a unix socket are not a valid octal number.
+#[derive(Debug, Fail)]
+#[fail(display = "Invalid socket permissions {}", _0)]
+pub struct InvalidPermissions(String);
+
+fn default_remove_stale() -> bool {
+    true
+}
+
+/// A description of a listening unix domain socket.
+///
+/// This is the unix socket counterpart of [`Listen`](struct.Listen.html).
+///
+/// It contains these configuration options:
+///
+/// * `path` (mandatory): Where in the file system the socket lives.
+/// * `permissions` (optional): The permissions of the socket, as an octal number in a string (for
+///   example `"0660"`). If not present, it is left to the umask.
+/// * `owner` and `group` (optional): User and group (name or numeric ID) to own the socket.
+///
+/// The socket is bound in a private temporary directory next to the path first and moved to the
+/// path only once it has the permissions and owner set, so it is never accessible to more users
+/// than configured.
+/// * `remove-stale`: If there's a socket at the path nobody listens on (left over after a crash,
+///   for example), remove it before binding. Defaults to `true`.
+///
+/// The socket file is not removed when the listener is closed or the application exits (an
+/// [upgraded](struct.Upgrade.html) process may still listen on it). It is replaced on the next
+/// bind, either as our own or as a stale one.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct ListenUnix {
+    path: PathBuf,
+    permissions: Option<String>,
+    #[serde(default)]
+    owner: SecId,
+    #[serde(default)]
+    group: SecId,
+    #[serde(rename = "remove-stale", default = "default_remove_stale")]
+    remove_stale: bool,
+}
+
+impl Default for ListenUnix {
+    fn default() -> Self {
+        ListenUnix {
+            path: PathBuf::new(),
+            permissions: None,
+            owner: SecId::Nothing,
+            group: SecId::Nothing,
+            remove_stale: default_remove_stale(),
+        }
+    }
+}
+
+/// Checks if nobody listens on the socket.
+fn is_stale(path: &Path) -> bool {
+    match StdUnixStream::connect(path) {
+        Err(ref e) => e.kind() == ErrorKind::ConnectionRefused,
+        Ok(_) => false,
+    }
+}
+
+/// Removes a socket left at the path, if it was ours or nobody listens on it.
+fn remove_old(path: &Path, ours: bool, remove_stale: bool) -> Result<(), Error> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    // Not a socket ‒ leave it alone and let the bind fail
+    if !meta.file_type().is_socket() {
+        return Ok(());
+    }
+    if ours {
+        debug!("Removing our previous socket at {}", path.display());
+    } else if remove_stale && is_stale(path) {
+        info!("Removing stale socket {}", path.display());
+    } else {
+        return Ok(());
+    }
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Binds the socket and places it at the path once it has the right permissions and owner.
+///
+/// The socket is bound in a private directory next to the path first, and linked into place
+/// after the access is set (the link fails if there's something at the path already).
+fn bind_private(path: &Path, access: &Access) -> Result<StdUnixListener, Error> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| format_err!("No file name in socket path {}", path.display()))?;
+    let mut dir_name = OsString::from(".");
+    dir_name.push(name);
+    dir_name.push(format!(".{}", process::id()));
+    let dir = path.with_file_name(dir_name);
+    let tmp = dir.join("socket");
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    let result = StdUnixListener::bind(&tmp)
+        .map_err(Error::from)
+        .and_then(|listener| {
+            access.apply(&tmp)?;
+            fs::hard_link(&tmp, path)?;
+            Ok(listener)
+        });
+    let _ = fs::remove_file(&tmp);
+    if let Err(e) = fs::remove_dir(&dir) {
+        warn!("Failed to remove temporary {}: {}", dir.display(), e);
+    }
+    result
+}
+
+/// Who can access the socket, resolved from the configuration.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Access {
+    mode: Option<u32>,
+    owner: Option<Uid>,
+    group: Option<Gid>,
+}
+
+impl Access {
+    fn apply(&self, path: &Path) -> Result<(), Error> {
+        if let Some(mode) = self.mode {
+            fs::set_permissions(path, Permissions::from_mode(mode))?;
+        }
+        if self.owner.is_some() || self.group.is_some() {
+            unistd::chown(path, self.owner, self.group)?;
+        }
+        Ok(())
+    }
+}
+
+impl ListenUnix {
+    fn access(&self) -> Result<Access, Error> {
+        let mode = match self.permissions {
+            Some(ref perm) => {
+                Some(u32::from_str_radix(perm, 8).map_err(|_| InvalidPermissions(perm.clone()))?)
+            }
+            None => None,
+        };
+        Ok(Access {
+            mode,
+            owner: self.owner.uid()?.map(Uid::from_raw),
+            group: self.group.gid()?.map(Gid::from_raw),
+        })
+    }
+
+    /// Binds the socket, or reuses our own one still alive at the same path.
+    fn bind(&self) -> Result<Arc<StdUnixListener>, Error> {
+        let mut bound = BOUND.lock();
+        let previous = bound.get(&self.path).map(Weak::upgrade);
+        if let Some(Some(listener)) = previous {
+            debug!("Reusing our socket at {}", self.path.display());
+            return Ok(listener);
+        }
+        let key = format!("unix {}", self.path.display());
+        let listener = match upgrade::adopt(&key)? {
+            Some(listener) => Arc::new(listener),
+            None => {
+                remove_old(&self.path, previous.is_some(), self.remove_stale)?;
+                Arc::new(bind_private(&self.path, &self.access()?)?)
+            }
+        };
+        bound.insert(self.path.clone(), Arc::downgrade(&listener));
+        upgrade::register(key, &listener);
+        Ok(listener)
+    }
+
+    /// Creates the listening socket described by the loaded configuration.
+    ///
+    /// If this process already listens on the same path, the same socket is returned. A socket
+    /// passed from the previous process during an [upgrade](struct.Upgrade.html) is used too.
+    pub fn create(&self) -> Result<Arc<StdUnixListener>, Error> {
+        let access = self.access()?;
+        let listener = self.bind()?;
+        access.apply(&self.path)?;
+        Ok(listener)
+    }
+}
+
+/// The parts of the configuration passed to the installed listener.
+#[derive(Clone, Debug, PartialEq)]
+struct Extra<ExtraCfg> {
+    cfg: ExtraCfg,
+    accept: Accept,
+    path: PathBuf,
+    access: Access,
+}
+
+/// A configuration fragment of a unix domain listening socket.
+///
+/// This works the same way as [`TcpListen`](struct.TcpListen.html), only the accepted connections
+/// are unix streams. The permissions and ownership are updated when the new configuration is
+/// used.
+///
+/// # Type parameters
+///
+/// * `ExtraCfg`: Any additional configuration options, passed to the action callback. Like with
+///   `TcpListen`, it should be a structure.
+/// * `ScaleMode`: A description of how to scale into multiple listening instances.
+///
+/// # Configuration options
+///
+/// Aside from the options from the type parameters above, these options are present:
+///
+/// * `path`, `permissions`, `owner`, `group` and `remove-stale`: Describe the socket itself (see
+///   [`ListenUnix`](struct.ListenUnix.html)).
+/// * `error-sleep-ms`, `max-conn`, `drain-timeout-ms`, `idle-timeout-ms` and
+///   `max-conn-lifetime-ms`: The same as in `TcpListen`.
+///
+/// # Examples
+///
+/// ```rust
+/// extern crate failure;
+/// #[macro_use]
+/// extern crate serde_derive;
+/// extern crate spirit;
+/// extern crate spirit_tokio;
+/// extern crate tokio;
+///
+/// use failure::Error;
+/// use spirit::{Empty, Spirit, SpiritInner};
+/// use spirit_tokio::{Tracked, UnixListen};
+/// use tokio::net::UnixStream;
+/// use tokio::prelude::*;
+///
+/// const DEFAULT_CONFIG: &str = r#"
+/// [control]
+/// path = "/tmp/spirit-tokio-unix-doc.sock"
+/// permissions = "0600"
+/// "#;
+///
+/// #[derive(Default, Deserialize)]
+/// struct Config {
+///     control: UnixListen,
+/// }
+///
+/// fn connection(
+///     _: &SpiritInner<Empty, Config>,
+///     conn: Tracked<UnixStream>,
+///     _: &Empty,
+/// ) -> impl Future<Item = (), Error = Error> {
+///     tokio::io::write_all(conn, "Hello\n")
+///         .map(|_| ())
+///         .map_err(Error::from)
+/// }
+///
+/// fn main() {
+///     Spirit::<_, Empty, _>::new(Config::default())
+///         .config_defaults(DEFAULT_CONFIG)
+///         .config_helper(|cfg: &Config| cfg.control.clone(), connection, "control")
+///         .run(|spirit| {
+/// #           spirit.terminate();
+///             Ok(())
+///         });
+/// }
+/// ```
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct UnixListen<ExtraCfg = Empty, ScaleMode: Scaled = Scale> {
+    #[serde(flatten)]
+    listen: ListenUnix,
+    #[serde(flatten)]
+    scale: ScaleMode,
+    #[serde(rename = "error-sleep-ms", default = "default_error_sleep")]
+    error_sleep_ms: u64,
+    #[serde(rename = "max-conn", default = "default_max_conn")]
+    max_conn: usize,
+    #[serde(rename = "drain-timeout-ms")]
+    drain_timeout_ms: Option<u64>,
+    #[serde(rename = "idle-timeout-ms")]
+    idle_timeout_ms: Option<u64>,
+    #[serde(rename = "max-conn-lifetime-ms")]
+    max_conn_lifetime_ms: Option<u64>,
+    #[serde(flatten)]
+    extra_cfg: ExtraCfg,
+    #[serde(flatten)]
+    unused: Unused,
+}
+
+impl<ExtraCfg: Default, ScaleMode: Default + Scaled> Default for UnixListen<ExtraCfg, ScaleMode> {
+    fn default() -> Self {
+        Self {
+            listen: ListenUnix::default(),
+            scale: ScaleMode::default(),
+            error_sleep_ms: default_error_sleep(),
+            max_conn: default_max_conn(),
+            drain_timeout_ms: None,
+            idle_timeout_ms: None,
+            max_conn_lifetime_ms: None,
+            extra_cfg: ExtraCfg::default(),
+            unused: Unused,
+        }
+    }
+}
+
+impl<ExtraCfg: Clone + Debug + PartialEq + Send + 'static> UnixListen<ExtraCfg> {
+    /// Provides a helper for this configuration.
+    ///
+    /// While you are free to use this directly, it is more commonly used through
+    /// `spirit::Builder::config_helper` with an extractor returning iterator of this type.
+    ///
+    /// # Parameters
+    ///
+    /// * `extract`: Closure that extracts an iterator of `UnixListen` out of the whole
+    ///   configuration.
+    /// * `conn`: An action to be taken on each accepted connection.
+    /// * `name`: How to call the instances in logs.
+    pub fn helper<Extract, ExtractIt, Conn, ConnFut, Name, S, O, C>(
+        mut extract: Extract,
+        conn: Conn,
+        name: Name,
+    ) -> impl Helper<S, O, C>
+    where
+        S: Borrow<ArcSwap<C>> + Sync + Send + 'static,
+        for<'de> C: Deserialize<'de> + Send + Sync + 'static,
+        O: Debug + StructOpt + Sync + Send + 'static,
+        Extract: FnMut(&C) -> ExtractIt + Send + 'static,
+        ExtractIt: IntoIterator<Item = Self>,
+        Conn: Fn(&Arc<Spirit<S, O, C>>, Tracked<UnixStream>, &ExtraCfg) -> ConnFut
+            + Sync
+            + Send
+            + 'static,
+        ConnFut: Future<Item = (), Error = Error> + Send + 'static,
+        Name: Clone + Display + Send + Sync + 'static,
+    {
+        let conn = Arc::new(conn);
+
+        let to_task_name = name.clone();
+        let to_task = move |spirit: &Arc<Spirit<S, O, C>>,
+                            listener: Arc<StdUnixListener>,
+                            extra: Extra<ExtraCfg>| {
+            let spirit = Arc::clone(spirit);
+            let conn = Arc::clone(&conn);
+            let name = to_task_name.clone();
+            let Extra {
+                cfg,
+                accept,
+                path,
+                access,
+            } = extra;
+            let pending = spirit.pending(format!("connections on {}", name));
+            // Set only now, when the configuration is accepted and used.
+            if let Err(e) = access.apply(&path) {
+                error!("Failed to set access to {}: {}", path.display(), e);
+            }
+            listener
+                .try_clone() // Another copy of the listener
+                // std → tokio socket conversion
+                .and_then(|listener| UnixListener::from_std(listener, &Handle::default()))
+                .map_err(Error::from)
+                .into_future()
+                .and_then(move |listener| {
+                    let handle = move |new_conn| conn(&spirit, new_conn, &cfg);
+                    accept_loop(listener.incoming(), accept, name, pending, handle)
+                })
+        };
+
+        let extract_name = name.clone();
+        let extract = move |cfg: &C| {
+            let name = extract_name.clone();
+            extract(cfg).into_iter().map(move |c| {
+                let (scale, mut results) = c.scale.scaled(&name);
+                let access = c.listen.access().unwrap_or_else(|e| {
+                    let path = c.listen.path.display();
+                    results.merge(ValidationResult::error(format!(
+                        "{} ({}): {}",
+                        name, path, e
+                    )));
+                    Access::default()
+                });
+                let extra = Extra {
+                    cfg: c.extra_cfg,
+                    accept: Accept {
+                        error_sleep: Duration::from_millis(c.error_sleep_ms),
+                        max_conn: c.max_conn,
+                        drain_timeout: c.drain_timeout_ms.map(Duration::from_millis),
+                        idle_timeout: c.idle_timeout_ms.map(Duration::from_millis),
+                        max_lifetime: c.max_conn_lifetime_ms.map(Duration::from_millis),
+                    },
+                    path: c.listen.path.clone(),
+                    access,
+                };
+                (c.listen, extra, scale, results)
+            })
+        };
+
+        Task {
+            extract,
+            build: ListenUnix::bind,
+            to_task,
+            name,
+        }
+    }
+}
+
+impl<S, O, C, Conn, ConnFut, ExtraCfg> IteratedCfgHelper<S, O, C, Conn> for UnixListen<ExtraCfg>
+where
+    S: Borrow<ArcSwap<C>> + Sync + Send + 'static,
+    for<'de> C: Deserialize<'de> + Send + Sync + 'static,
+    O: Debug + StructOpt + Sync + Send + 'static,
+    ExtraCfg: Clone + Debug + PartialEq + Send + 'static,
+    Conn: Fn(&Arc<Spirit<S, O, C>>, Tracked<UnixStream>, &ExtraCfg) -> ConnFut
+        + Sync
+        + Send
+        + 'static,
+    ConnFut: Future<Item = (), Error = Error> + Send + 'static,
+{
+    fn apply<Extractor, ExtractedIter, Name>(
+        extractor: Extractor,
+        action: Conn,
+        name: Name,
+        builder: Builder<S, O, C>,
+    ) -> Builder<S, O, C>
+    where
+        Extractor: FnMut(&C) -> ExtractedIter + Send + 'static,
+        ExtractedIter: IntoIterator<Item = Self>,
+        Name: Clone + Display + Send + Sync + 'static,
+    {
+        Self::helper(extractor, action, name).apply(builder)
+    }
+}
+
+impl<S, O, C, Conn, ConnFut, ExtraCfg> CfgHelper<S, O, C, Conn> for UnixListen<ExtraCfg>
+where
+    S: Borrow<ArcSwap<C>> + Sync + Send + 'static,
+    for<'de> C: Deserialize<'de> + Send + Sync + 'static,
+    O: Debug + StructOpt + Sync + Send + 'static,
+    ExtraCfg: Clone + Debug + PartialEq + Send + 'static,
+    Conn: Fn(&Arc<Spirit<S, O, C>>, Tracked<UnixStream>, &ExtraCfg) -> ConnFut
+        + Sync
+        + Send
+        + 'static,
+    ConnFut: Future<Item = (), Error = Error> + Send + 'static,
+{
+    fn apply<Extractor, Name>(
+        mut extractor: Extractor,
+        action: Conn,
+        name: Name,
+        builder: Builder<S, O, C>,
+    ) -> Builder<S, O, C>
+    where
+        Extractor: FnMut(&C) -> Self + Send + 'static,
+        Name: Clone + Display + Send + Sync + 'static,
+    {
+        let extractor = move |cfg: &_| iter::once(extractor(cfg));
+        Self::helper(extractor, action, name).apply(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn created_with_access() {
+        let dir = env::temp_dir().join(format!("spirit-unix-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("socket");
+        let access = Access {
+            mode: Some(0o600),
+            ..Access::default()
+        };
+        let _listener = bind_private(&path, &access).unwrap();
+        let meta = fs::symlink_metadata(&path).unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(0o600, meta.permissions().mode() & 0o777);
+        StdUnixStream::connect(&path).unwrap();
+        // The temporary directory is gone and a second socket doesn't replace the first one
+        assert_eq!(1, fs::read_dir(&dir).unwrap().count());
+        assert!(bind_private(&path, &access).is_err());
+        assert_eq!(1, fs::read_dir(&dir).unwrap().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,395 @@
+//! Handing the listening sockets over to a new version of the application.
+//!
+//! The running process starts a new one, passing it copies of its listening sockets together
+//! with a mapping describing which configuration each of them belongs to. The new process uses
+//! them instead of binding new sockets and once it is ready, it asks the old one to terminate.
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fmt::{Debug, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+
+use failure::Error;
+use lazy_static;
+use listenfd::ListenFd;
+use nix::libc;
+use nix::sys::signal::{self, Signal};
+use nix::sys::socket::{self, sockopt};
+use nix::unistd::{self, Pid};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use spirit::helpers::Helper;
+use spirit::{ArcSwap, Builder};
+use structopt::StructOpt;
+
+use activation::{Take, INHERITED};
+
+/// Lines of `fd key` of the passed sockets.
+const UPGRADE_FDS: &str = "SPIRIT_UPGRADE_FDS";
+/// The process to terminate once we are ready.
+const UPGRADE_PARENT: &str = "SPIRIT_UPGRADE_PARENT";
+
+type Live = Vec<(String, Weak<AsRawFd + Send + Sync>)>;
+
+/// A socket passed from the previous process.
+///
+/// The listenfd checks it is of the right type and converts it, the raw descriptor is kept to be
+/// able to close it if nobody wants it.
+struct PassedFd {
+    fd: RawFd,
+    fds: ListenFd,
+}
+
+impl PassedFd {
+    /// Hands the descriptor over to listenfd.
+    ///
+    /// The listenfd takes the sockets from the environment only, so it's set for it. This happens
+    /// once, during the startup, before any other threads exist.
+    fn new(fd: RawFd) -> Self {
+        env::set_var("LISTEN_FDS", "1");
+        env::set_var("LISTEN_FDS_FIRST_FD", fd.to_string());
+        env::remove_var("LISTEN_PID");
+        let fds = ListenFd::from_env();
+        env::remove_var("LISTEN_FDS_FIRST_FD");
+        PassedFd { fd, fds }
+    }
+}
+
+/// The passed sockets nobody adopted yet.
+struct Passed(HashMap<String, Vec<PassedFd>>);
+
+impl Passed {
+    fn from_env() -> Self {
+        let mut passed = HashMap::new();
+        let fds = match env::var(UPGRADE_FDS) {
+            Ok(fds) => fds,
+            Err(_) => return Passed(passed),
+        };
+        env::remove_var(UPGRADE_FDS);
+        debug!("Passed sockets from the previous process: {:?}", fds);
+        for line in fds.lines() {
+            let mut parts = line.splitn(2, ' ');
+            match (parts.next().map(str::parse), parts.next()) {
+                (Some(Ok(fd)), Some(key)) => {
+                    passed
+                        .entry(key.to_owned())
+                        .or_insert_with(Vec::new)
+                        .push(PassedFd::new(fd));
+                }
+                _ => warn!("Invalid passed socket {:?}", line),
+            }
+        }
+        Passed(passed)
+    }
+}
+
+lazy_static! {
+    /// The sockets we listen on, under the keys of their configuration.
+    static ref LIVE: Mutex<Live> = Mutex::new(Vec::new());
+    static ref PASSED: Mutex<Passed> = {
+        // Let the socket activation get its sockets first, we use the same variables for ours.
+        lazy_static::initialize(&INHERITED);
+        Mutex::new(Passed::from_env())
+    };
+}
+
+/// Takes the passed sockets from the environment.
+///
+/// Called by the helpers when they are being set up, so the environment is read and cleaned up
+/// before there are other threads.
+pub(crate) fn init() {
+    lazy_static::initialize(&PASSED);
+}
+
+/// Remembers a listening socket, so it can be passed to the new process on upgrade.
+pub(crate) fn register<T: AsRawFd + Send + Sync + 'static>(key: String, socket: &Arc<T>) {
+    let fd = socket.as_raw_fd();
+    let mut live = LIVE.lock();
+    live.retain(|(_, socket)| socket.upgrade().is_some());
+    let known = live
+        .iter()
+        .any(|(_, socket)| socket.upgrade().map(|s| s.as_raw_fd()) == Some(fd));
+    if !known {
+        let socket: Arc<AsRawFd + Send + Sync> = Arc::<T>::clone(socket);
+        live.push((key, Arc::downgrade(&socket)));
+    }
+}
+
+/// Adopts a socket passed from the previous process for this configuration, if there's one.
+///
+/// The socket gets the close-on-exec flag, so it doesn't leak into our own children.
+pub(crate) fn adopt<T: Take>(key: &str) -> Result<Option<T>, Error> {
+    let mut passed = PASSED.lock();
+    let PassedFd { fd, mut fds } = match passed.0.get_mut(key).and_then(Vec::pop) {
+        Some(passed) => passed,
+        None => return Ok(None),
+    };
+    let listens = !T::LISTENS || socket::getsockopt(fd, sockopt::AcceptConn).unwrap_or(false);
+    let socket = if listens {
+        T::take(&mut fds, 0)
+            .map_err(Error::from)
+            .and_then(|socket| socket.ok_or_else(|| format_err!("Socket {} taken twice", key)))
+    } else {
+        Err(format_err!("Passed socket {} is not listening", key))
+    };
+    match socket {
+        Ok(socket) => {
+            debug!("Adopted socket {} from the previous process", key);
+            Ok(Some(socket))
+        }
+        Err(e) => {
+            let _ = unistd::close(fd);
+            Err(format_err!("Can't use passed socket {}: {}", key, e))
+        }
+    }
+}
+
+/// Closes the passed sockets the new configuration doesn't use.
+fn close_unused() {
+    for (key, fds) in PASSED.lock().0.drain() {
+        for PassedFd { fd, .. } in fds {
+            debug!("Closing unused passed socket {}", key);
+            if let Err(e) = unistd::close(fd) {
+                warn!("Failed to close passed socket {}: {}", key, e);
+            }
+        }
+    }
+}
+
+/// Copies of the sockets for the new process, closed in this one once it's started.
+struct Dups(Vec<RawFd>);
+
+impl Drop for Dups {
+    fn drop(&mut self) {
+        for fd in &self.0 {
+            let _ = unistd::close(*fd);
+        }
+    }
+}
+
+/// How to start the new process.
+struct Exec {
+    exe: PathBuf,
+    args: Vec<OsString>,
+    cwd: PathBuf,
+}
+
+impl Exec {
+    fn spawn(&self) -> Result<process::Child, Error> {
+        // The copies have the close-on-exec flag cleared, so they get inherited.
+        let mut dups = Dups(Vec::new());
+        let mut mapping = String::new();
+        for (key, socket) in LIVE.lock().iter() {
+            if let Some(socket) = socket.upgrade() {
+                let fd = unistd::dup(socket.as_raw_fd())?;
+                dups.0.push(fd);
+                writeln!(mapping, "{} {}", fd, key).expect("Writing to string doesn't fail");
+            }
+        }
+        info!(
+            "Starting new version {} with {} sockets",
+            self.exe.display(),
+            dups.0.len()
+        );
+        let child = Command::new(&self.exe)
+            .args(&self.args)
+            .current_dir(&self.cwd)
+            .env(UPGRADE_FDS, mapping)
+            .env(UPGRADE_PARENT, process::id().to_string())
+            .spawn()?;
+        Ok(child)
+    }
+}
+
+/// Tells the previous process we are ready to take over.
+fn take_over(parent: &str) {
+    match parent.parse() {
+        Ok(pid) => {
+            info!("Upgrade complete, terminating the previous process {}", pid);
+            if let Err(e) = signal::kill(Pid::from_raw(pid), Signal::SIGTERM) {
+                error!("Failed to terminate the previous process {}: {}", pid, e);
+            }
+        }
+        Err(e) => warn!("Invalid previous process {:?}: {}", parent, e),
+    }
+}
+
+/// A helper for a zero-downtime upgrade of the application binary.
+///
+/// On `SIGUSR2`, the application starts the binary it was started from (presumably already
+/// replaced by a new version) with the same arguments. The listening sockets of the
+/// [`TcpListen`](struct.TcpListen.html), [`UdpListen`](struct.UdpListen.html) and
+/// [`UnixListen`](struct.UnixListen.html) helpers are passed to it, so it uses them instead of
+/// binding new ones (each socket is used by the same configuration as in the old process, the
+/// ones no longer configured are closed).
+///
+/// Once the new process is ready (its [before-bodies] registered *before* this helper finished),
+/// it terminates the old one, which drains its connections the usual way. If the new process
+/// fails to start, the old one keeps running.
+///
+/// Note that the new process is a child of the old one, therefore this doesn't go well with
+/// supervisors tracking the main process (like systemd). It is meant for applications managing
+/// themselves (for example with `--daemonize`).
+///
+/// [before-bodies]: https://docs.rs/spirit/*/spirit/struct.Builder.html#method.before_body
+///
+/// # Examples
+///
+/// ```rust
+/// extern crate spirit;
+/// extern crate spirit_tokio;
+///
+/// use spirit::{Empty, Spirit};
+/// use spirit_tokio::Upgrade;
+///
+/// fn main() {
+///     Spirit::<_, Empty, _>::new(Empty {})
+///         // The socket helpers go here
+///         .with(Upgrade)
+///         .run(|spirit| {
+/// #           spirit.terminate();
+///             Ok(())
+///         });
+/// }
+/// ```
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Upgrade;
+
+impl<S, O, C> Helper<S, O, C> for Upgrade
+where
+    S: Borrow<ArcSwap<C>> + Sync + Send + 'static,
+    for<'de> C: Deserialize<'de> + Send + Sync + 'static,
+    O: Debug + StructOpt + Sync + Send + 'static,
+{
+    fn apply(self, builder: Builder<S, O, C>) -> Builder<S, O, C> {
+        // The environment is read (and cleaned up for our children) now, while we're the only
+        // thread.
+        init();
+        let parent = env::var(UPGRADE_PARENT).ok();
+        env::remove_var(UPGRADE_PARENT);
+        // Taken now, before daemonization changes the working directory.
+        let exec = match (env::current_exe(), env::current_dir()) {
+            (Ok(exe), Ok(cwd)) => Exec {
+                exe,
+                args: env::args_os().skip(1).collect(),
+                cwd,
+            },
+            (Err(e), _) | (_, Err(e)) => {
+                warn!("Upgrades are not available: {}", e);
+                return builder;
+            }
+        };
+        let exec = Arc::new(exec);
+        let upgrading = Arc::new(AtomicBool::new(false));
+        builder
+            .on_signal(libc::SIGUSR2, move || {
+                if upgrading.swap(true, Ordering::Relaxed) {
+                    warn!("Upgrade already in progress");
+                    return;
+                }
+                let mut child = match exec.spawn() {
+                    Ok(child) => child,
+                    Err(e) => {
+                        error!("Failed to start the new version: {}", e);
+                        upgrading.store(false, Ordering::Relaxed);
+                        return;
+                    }
+                };
+                let upgrading = Arc::clone(&upgrading);
+                let waiting = thread::Builder::new()
+                    .name("spirit-upgrade".to_owned())
+                    .spawn(move || match child.wait() {
+                        // With daemonization, the new version continues in another process.
+                        Ok(ref status) if status.success() => (),
+                        Ok(status) => {
+                            error!("The new version failed ({}), keeping this one", status);
+                            upgrading.store(false, Ordering::Relaxed);
+                        }
+                        Err(e) => error!("Failed to wait for the new version: {}", e),
+                    });
+                if let Err(e) = waiting {
+                    warn!("Can't watch the new version: {}", e);
+                }
+            })
+            .before_body(move |_| {
+                close_unused();
+                if let Some(parent) = parent {
+                    take_over(&parent);
+                }
+                Ok(())
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::net::{TcpListener, UdpSocket};
+    use std::os::unix::net::UnixListener;
+
+    use nix::fcntl::{self, FcntlArg, FdFlag};
+
+    use activation::tests::ENV;
+
+    use super::*;
+
+    fn pass<T: AsRawFd>(key: &str, socket: &T) {
+        let fd = unistd::dup(socket.as_raw_fd()).unwrap();
+        let _env = ENV.lock();
+        let passed = PassedFd::new(fd);
+        PASSED
+            .lock()
+            .0
+            .entry(key.to_owned())
+            .or_default()
+            .push(passed);
+    }
+
+    #[test]
+    fn adopt_passed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        pass("tcp adopt", &listener);
+        let adopted = adopt::<TcpListener>("tcp adopt").unwrap().unwrap();
+        assert_eq!(
+            listener.local_addr().unwrap(),
+            adopted.local_addr().unwrap()
+        );
+        // Taken out, so nothing more to adopt
+        assert!(adopt::<TcpListener>("tcp adopt").unwrap().is_none());
+        assert!(adopt::<TcpListener>("tcp unknown").unwrap().is_none());
+    }
+
+    #[test]
+    fn adopt_cloexec() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        pass("udp cloexec", &socket);
+        let adopted = adopt::<UdpSocket>("udp cloexec").unwrap().unwrap();
+        let flags = fcntl::fcntl(adopted.as_raw_fd(), FcntlArg::F_GETFD).unwrap();
+        assert!(FdFlag::from_bits_truncate(flags).contains(FdFlag::FD_CLOEXEC));
+    }
+
+    #[test]
+    fn adopt_wrong_type() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        pass("tcp wrong", &listener);
+        assert!(adopt::<UdpSocket>("tcp wrong").is_err());
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        pass("udp wrong", &socket);
+        assert!(adopt::<TcpListener>("udp wrong").is_err());
+        let path = env::temp_dir().join(format!("spirit-upgrade-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let unix = UnixListener::bind(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        pass("unix wrong", &unix);
+        assert!(adopt::<TcpListener>("unix wrong").is_err());
+        pass("unix right", &unix);
+        assert!(adopt::<UnixListener>("unix right").unwrap().is_some());
+    }
+}
@@ -0,0 +1,79 @@
+//! Checking the configuration with `--check-config` while the application already runs.
+//!
+//! Spirit takes its command line from the process and sets up process-wide things (the logger,
+//! signal handlers), so the application runs in a child process started from this one.
+
+extern crate failure;
+#[macro_use]
+extern crate serde_derive;
+extern crate spirit;
+extern crate spirit_tokio;
+extern crate tokio;
+
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{self, Command};
+
+use failure::Error;
+use spirit::{Empty, Spirit, SpiritInner};
+use spirit_tokio::{TcpListen, Tracked};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+
+const CHILD: &str = "SPIRIT_TEST_CHILD";
+
+#[derive(Default, Deserialize)]
+struct Config {
+    listen: TcpListen,
+}
+
+fn connection(
+    _: &SpiritInner<Empty, Config>,
+    _: Tracked<TcpStream>,
+    _: &Empty,
+) -> impl Future<Item = (), Error = Error> {
+    future::ok(())
+}
+
+fn child() {
+    Spirit::<_, Empty, _>::new(Config::default())
+        .config_helper(|cfg: &Config| cfg.listen.clone(), connection, "listen")
+        .run(|spirit| {
+            spirit.terminate();
+            Ok(())
+        });
+}
+
+/// Runs the application with the configuration, returns if it succeeded.
+fn run(config: &str, check: bool) -> bool {
+    let path: PathBuf = env::temp_dir().join(format!("spirit-check-{}.toml", process::id()));
+    fs::write(&path, config).unwrap();
+    let mut command = Command::new(env::current_exe().unwrap());
+    command.env(CHILD, "1");
+    if check {
+        command.arg("--check-config");
+    }
+    let status = command.arg(&path).status().unwrap();
+    fs::remove_file(&path).unwrap();
+    status.success()
+}
+
+fn main() {
+    if env::var(CHILD).is_ok() {
+        return child();
+    }
+    // The running instance of the application
+    let running = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = running.local_addr().unwrap().port();
+    let config = format!("[listen]\nhost = \"127.0.0.1\"\nport = {}\n", port);
+    assert!(
+        run(&config, true),
+        "The check collides with the running instance"
+    );
+    assert!(!run(&config, false), "The port is not taken");
+    println!("Taken port OK");
+    assert!(!run("[listen]\nhost = \"127.0.0.1\"\n", true));
+    println!("Missing port OK");
+}
@@ -0,0 +1,120 @@
+//! Draining the connections of a listener removed from the configuration.
+//!
+//! Spirit takes its command line from the process and sets up process-wide things (the logger,
+//! signal handlers), so the application runs in a child process started from this one.
+
+extern crate failure;
+extern crate nix;
+#[macro_use]
+extern crate serde_derive;
+extern crate spirit;
+extern crate spirit_tokio;
+extern crate tokio;
+
+use std::env;
+use std::fs;
+use std::io::{ErrorKind, Read};
+use std::net::{TcpListener, TcpStream as StdTcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use failure::Error;
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use spirit::{Empty, Spirit, SpiritInner};
+use spirit_tokio::{TcpListen, Tracked};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+
+const CHILD: &str = "SPIRIT_TEST_CHILD";
+
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(1000);
+
+#[derive(Default, Deserialize)]
+struct Config {
+    listen: Vec<TcpListen>,
+}
+
+/// Keeps the connection open until the client closes it.
+fn connection(
+    _: &SpiritInner<Empty, Config>,
+    conn: Tracked<TcpStream>,
+    _: &Empty,
+) -> impl Future<Item = (), Error = Error> {
+    tokio::io::read_to_end(conn, Vec::new())
+        .map(|_| ())
+        .map_err(Error::from)
+}
+
+fn child() {
+    Spirit::<_, Empty, _>::new(Config::default())
+        .config_helper(|cfg: &Config| cfg.listen.clone(), connection, "listen")
+        .run(|_| Ok(()));
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn write_config(path: &Path, ports: &[u16]) {
+    let config = ports
+        .iter()
+        .map(|port| {
+            format!(
+                "[[listen]]\nhost = \"127.0.0.1\"\nport = {}\ndrain-timeout-ms = {}\n",
+                port,
+                DRAIN_TIMEOUT.as_millis()
+            )
+        })
+        .collect::<String>();
+    fs::write(path, config).unwrap();
+}
+
+fn main() {
+    if env::var(CHILD).is_ok() {
+        return child();
+    }
+    let config: PathBuf = env::temp_dir().join(format!("spirit-drain-{}.toml", process::id()));
+    let (removed, kept) = (free_port(), free_port());
+    write_config(&config, &[removed, kept]);
+    let mut child = Command::new(env::current_exe().unwrap())
+        .env(CHILD, "1")
+        .arg(&config)
+        .spawn()
+        .unwrap();
+    let pid = Pid::from_raw(child.id() as i32);
+    // Wait for it to start listening
+    thread::sleep(Duration::from_secs(1));
+    let mut conn = StdTcpStream::connect(("127.0.0.1", removed)).unwrap();
+    // Let it get accepted
+    thread::sleep(Duration::from_millis(100));
+
+    write_config(&config, &[kept]);
+    signal::kill(pid, Signal::SIGHUP).unwrap();
+    let removed_at = Instant::now();
+    let mut buf = [0; 1];
+    // The connection stays open for a while
+    conn.set_read_timeout(Some(DRAIN_TIMEOUT / 2)).unwrap();
+    let err = conn.read(&mut buf).unwrap_err();
+    assert!(err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut);
+    assert!(StdTcpStream::connect(("127.0.0.1", removed)).is_err());
+    println!("Connection kept OK");
+    // But gets cut after the timeout
+    conn.set_read_timeout(Some(DRAIN_TIMEOUT * 3)).unwrap();
+    assert_eq!(0, conn.read(&mut buf).unwrap());
+    let elapsed = removed_at.elapsed();
+    assert!(elapsed >= DRAIN_TIMEOUT, "Cut after {:?}", elapsed);
+    assert!(elapsed < DRAIN_TIMEOUT * 2, "Cut after {:?}", elapsed);
+    println!("Connection cut OK");
+
+    signal::kill(pid, Signal::SIGTERM).unwrap();
+    let status = child.wait().unwrap();
+    fs::remove_file(&config).unwrap();
+    assert!(status.success());
+}
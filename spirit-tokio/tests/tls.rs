@@ -0,0 +1,135 @@
+//! Replacing the certificate of a running `TlsListen`.
+//!
+//! Spirit takes its command line from the process and sets up process-wide things (the logger,
+//! signal handlers), so the application runs in a child process started from this one.
+
+extern crate failure;
+extern crate nix;
+extern crate openssl;
+#[macro_use]
+extern crate serde_derive;
+extern crate spirit;
+extern crate spirit_tokio;
+extern crate tokio;
+
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::net::{TcpListener, TcpStream as StdTcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::thread;
+use std::time::Duration;
+
+use failure::Error;
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use openssl::hash::MessageDigest;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use openssl::x509::X509;
+use spirit::{Empty, Spirit, SpiritInner};
+use spirit_tokio::{TlsListen, TlsStream, Tracked};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+
+const CHILD: &str = "SPIRIT_TEST_CHILD";
+
+#[derive(Default, Deserialize)]
+struct Config {
+    listen: TlsListen,
+}
+
+fn connection(
+    _: &SpiritInner<Empty, Config>,
+    conn: TlsStream<Tracked<TcpStream>>,
+    _: &Empty,
+) -> impl Future<Item = (), Error = Error> {
+    tokio::io::write_all(conn, "Hello\n")
+        .map(|_| ())
+        .map_err(Error::from)
+}
+
+fn child() {
+    Spirit::<_, Empty, _>::new(Config::default())
+        .config_helper(|cfg: &Config| cfg.listen.clone(), connection, "listen")
+        .run(|_| Ok(()));
+}
+
+/// Makes a TLS connection, returns the fingerprint of the server certificate.
+fn handshake(port: u16) -> Vec<u8> {
+    let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+    connector.set_verify(SslVerifyMode::NONE);
+    let stream = StdTcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut tls = connector.build().connect("localhost", stream).unwrap();
+    let mut hello = String::new();
+    tls.read_to_string(&mut hello).unwrap();
+    assert_eq!("Hello\n", hello);
+    let cert = tls.ssl().peer_certificate().unwrap();
+    cert.digest(MessageDigest::sha256()).unwrap().to_vec()
+}
+
+fn fingerprint(path: &Path) -> Vec<u8> {
+    let cert = X509::from_pem(&fs::read(path).unwrap()).unwrap();
+    cert.digest(MessageDigest::sha256()).unwrap().to_vec()
+}
+
+/// The inode of the socket listening on the port, to tell if it's still the same one.
+fn listening_inode(port: u16) -> String {
+    let local = format!(":{:04X}", port);
+    let table = fs::read_to_string("/proc/net/tcp").unwrap();
+    table
+        .lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|fields| fields[1].ends_with(&local) && fields[3] == "0A")
+        .map(|fields| fields[9].to_owned())
+        .expect("Nothing listens on the port")
+}
+
+fn main() {
+    if env::var(CHILD).is_ok() {
+        return child();
+    }
+    let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls");
+    let dir: PathBuf = env::temp_dir().join(format!("spirit-tls-{}", process::id()));
+    fs::create_dir(&dir).unwrap();
+    let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+    fs::copy(data.join("cert1.pem"), &cert).unwrap();
+    fs::copy(data.join("key1.pem"), &key).unwrap();
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let config = dir.join("config.toml");
+    let content = format!(
+        "[listen]\nhost = \"127.0.0.1\"\nport = {}\ncert-chain = {:?}\nprivate-key = {:?}\n",
+        port, cert, key
+    );
+    fs::write(&config, content).unwrap();
+    let mut child = Command::new(env::current_exe().unwrap())
+        .env(CHILD, "1")
+        .arg(&config)
+        .spawn()
+        .unwrap();
+    let pid = Pid::from_raw(child.id() as i32);
+    // Wait for it to start listening
+    thread::sleep(Duration::from_secs(1));
+
+    assert_eq!(fingerprint(&cert), handshake(port));
+    let inode = listening_inode(port);
+    println!("Handshake OK");
+
+    fs::copy(data.join("cert2.pem"), &cert).unwrap();
+    fs::copy(data.join("key2.pem"), &key).unwrap();
+    signal::kill(pid, Signal::SIGHUP).unwrap();
+    thread::sleep(Duration::from_secs(1));
+    assert_eq!(fingerprint(&cert), handshake(port));
+    assert_eq!(inode, listening_inode(port), "The socket got replaced");
+    println!("Certificate replacement OK");
+
+    signal::kill(pid, Signal::SIGTERM).unwrap();
+    let status = child.wait().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(status.success());
+}
@@ -50,7 +50,8 @@
 //!
 //! The background thread listens to certain signals (like `SIGHUP`) using the [signal-hook] crate
 //! and reloads the configuration when requested. It manages the logging backend to reopen on
-//! `SIGHUP` and reflect changes to the configuration.
+//! `SIGHUP` and reflect changes to the configuration. Optionally, it can also watch the
+//! configuration files and reload them when they change.
 //!
 //! [`Spirit`]: struct.Spirit.html
 //! [StructOpt]: https://crates.io/crates/structopt
@@ -130,6 +131,17 @@
 //! * `log`: In addition to the logging in configuration file, also log with the given severity to
 //!   stderr.
 //! * `log-module`: Override the stderr log level of the given module.
+//! * `dump-config`: Load the configuration (from all the sources, including the configuration
+//!   overrides), print the merged result in the given format (`toml` or `json`) and exit. This
+//!   helps to find out what the application actually sees. The `daemon` and `logging` sections
+//!   are printed with their defaults filled in, the rest of the configuration as loaded.
+//! * `check-config`: Load the configuration, run all the
+//!   [validators](struct.Builder.html#method.config_validator) on it, print their messages and
+//!   exit. The exit status is non-zero if the configuration contains errors. The application is
+//!   not daemonized, loggers are not switched and the body is not run. The validators are told
+//!   not to prepare any resources (see [`check_only`](validation/fn.check_only.html)), so the
+//!   check doesn't collide with an already running instance. Whatever they prepare anyway is
+//!   rolled back by their abort actions.
 //!
 //! Furthermore, it takes a list of paths ‒ both files and directories. They are loaded as
 //! configuration files (the directories are examined and files in them ‒ the ones passing a
@@ -150,16 +162,48 @@
 //!   `TRACE`.
 //! * `per-module`: A map, setting log level overrides for specific modules (logging targets). This
 //!   one is optional.
+//! * `format`: How the messages are formatted (ignored for `syslog`). The default `text` is meant
+//!   for humans. With `json`, each message is a JSON object on a single line, with the
+//!   `timestamp` (RFC 3339), `level`, `target`, `module`, `file`, `line`, `thread` (name) and
+//!   `message` fields (and `pid` if enabled).
+//! * `template`: The layout of the `text` format, like
+//!   `"{time:%H:%M:%S%.3f} [{level}] {target}: {message}"`. The placeholders are `time`, `level`,
+//!   `target`, `module`, `file`, `line`, `thread`, `pid` and `message`. The `time` can be given
+//!   its own time format after the colon, the others a width to pad to (`{level:5}`). Literal
+//!   braces are written as `{{` and `}}`.
+//! * `timezone`: Either `local` (the default) or `utc`.
+//! * `time-format`: The format of the timestamps, either in the [strftime-like
+//!   syntax](https://docs.rs/chrono/*/chrono/format/strftime/index.html) or `rfc3339`. Defaults to
+//!   `%Y-%m-%d %H:%M:%S%.3f`.
+//! * `thread`, `pid`: If set to `true`, the name of the thread or the PID is included in the
+//!   messages. For the `text` format, this is only for the default template, a custom one needs
+//!   to use the placeholders.
 //! * `type`: Specifies the type of logger destination. Some of them allow specifying other
 //!   options.
 //!
 //! The allowed types are:
-//! * `stdout`: The logs are sent to standard output. There are no additional options.
-//! * `stderr`: The logs are sent to standard error output. There are no additional options.
+//! * `stdout`: The logs are sent to standard output.
+//!   - `color`: One of `auto` (the default), `always` and `never`. With `auto`, the levels are
+//!     colored if the output is a terminal. Only the `text` format is colored.
+//!   - `color-target`: If set to `true`, the target is colored too.
+//! * `stderr`: The logs are sent to standard error output. The options are the same as with
+//!   `stdout`.
 //! * `file`: Logs are written to a file. The file is reopened every time a configuration is
-//!   re-read (therefore every time the application gets `SIGHUP`), which makes it work with
-//!   logrotate.
+//!   re-read (therefore every time the application gets `SIGHUP`) and, if the application sets
+//!   one, on the [`log_reopen_signal`](struct.Builder.html#method.log_reopen_signal), which makes
+//!   it work with logrotate.
 //!   - `filename`: The path to the file where to put the logs.
+//!   - `copytruncate`: If set to `true`, the file is checked before each message and reopened
+//!     if it got shorter, for the `copytruncate` mode of logrotate. The file is always written
+//!     in the append mode, so the new messages don't leave a hole at the start of the truncated
+//!     file.
+//!   - `rotate-size`: If set, the file is rotated once it grows to this many bytes.
+//!   - `rotate-interval`: If set to `hourly` or `daily`, the file is rotated at the start of each
+//!     hour or day (in local time).
+//!   - `keep`: How many rotated files to keep, defaults to 5. They are named by appending `.1`
+//!     (the newest one), `.2`, … to the `filename`.
+//!   - `compress`: If set to `true`, the rotated files are compressed by gzip (and get the `.gz`
+//!     suffix).
 //! * `network`: The application connects to a given host and port over TCP and sends logs there.
 //!   - `host`: The hostname (or IP address) to connect to.
 //!   - `port`: The port to use.
@@ -169,11 +213,21 @@
 //!
 //! Influences how daemonization is done.
 //!
-//! * `user`: The user to become. Either a numeric ID or name (not yet implemented). If not
-//!   present, it doesn't change the user.
-//! * `group`: Similar as user, but with group.
-//! * `pid_file`: A pid file to write on startup. If not present, nothing is stored.
+//! * `user`: The user to become. Either a numeric ID or name. If not present, it doesn't change
+//!   the user. If running as root, the supplementary groups are set to the ones of the user.
+//! * `group`: Similar as user, but with group. If not present and the `user` is set, the primary
+//!   group of the user is used.
+//! * `pid-file`: A pid file to write on startup. If not present, nothing is stored.
 //! * `workdir`: A working directory it'll switch into. If not set, defaults to `/`.
+//! * `shutdown-timeout-ms`: How long the application may take to shut down after being
+//!   terminated. After that, it logs what is still running and exits forcefully (see the
+//!   [`shutdown`](shutdown/index.html) module). If not set, it waits as long as it takes. Unlike
+//!   the other options, this one can be changed at runtime.
+//!
+//! ### Unused keys
+//!
+//! Keys in the configuration that nothing uses (often typos) are reported as warnings, see
+//! [`config_unused_keys`](struct.Builder.html#method.config_unused_keys).
 //!
 //! # Multithreaded applications
 //!
@@ -191,52 +245,67 @@ extern crate config;
 extern crate failure;
 extern crate fallible_iterator;
 extern crate fern;
+extern crate flate2;
 extern crate itertools;
+#[macro_use]
+extern crate lazy_static;
 extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate log_panics;
 extern crate log_reroute;
 extern crate nix;
+extern crate notify;
 extern crate parking_lot;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_ignored;
+extern crate serde_json;
 extern crate signal_hook;
 // For some reason, this produces a warning about unused on nightly… but it is needed on stable
 #[allow(unused_imports)]
 #[macro_use]
 extern crate structopt;
 extern crate syslog;
+extern crate toml;
+extern crate users;
 
 pub mod helpers;
+mod logfile;
+mod logformat;
 mod logging;
+pub mod shutdown;
+pub mod stats;
+pub mod systemd;
 pub mod validation;
+mod watch;
 
 use std::any::TypeId;
 use std::borrow::Borrow;
 use std::collections::{HashMap, HashSet};
 use std::env;
-use std::ffi::OsString;
-use std::fmt::Debug;
+use std::ffi::{CString, OsString};
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
 use std::fs::OpenOptions;
 use std::io::Write;
 use std::iter;
 use std::marker::PhantomData;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::AsRawFd;
 use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
 use std::process;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 pub use arc_swap::ArcSwap;
 use arc_swap::Lease;
-use config::{Config, Environment, File, FileFormat};
+use config::{Config, ConfigError, Environment, File, FileFormat, Source, Value};
 use failure::{Error, Fail};
 use fallible_iterator::FallibleIterator;
 use log::LevelFilter;
@@ -244,22 +313,34 @@ use nix::sys::stat::{self, Mode};
 use nix::unistd::{self, ForkResult, Gid, Uid};
 use parking_lot::Mutex;
 use serde::Deserialize;
+use serde_ignored::Path as IgnoredPath;
 use signal_hook::iterator::Signals;
 use structopt::clap::App;
 use structopt::StructOpt;
+use users::User;
 
+use logformat::{Coloring, Formatting};
 use logging::{LogDestination, Logging};
 use validation::{
-    Error as ValidationError, Level as ValidationLevel, Results as ValidationResults,
+    Error as ValidationError, Level as ValidationLevel, Result as ValidationResult,
+    Results as ValidationResults,
 };
 
+pub use logformat::LogFormatError;
 pub use logging::SyslogError;
 
-#[derive(Debug, Deserialize, Eq, PartialEq)]
+/// A user or group, as written in the configuration.
+///
+/// It can be either a name or a numeric ID. This is used for the `user` and `group` options of the
+/// `daemon` section, but it may be useful for other places (for example, owners of files).
+#[derive(Clone, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
 #[serde(untagged)]
-enum SecId {
+pub enum SecId {
+    /// A name to look up.
     Name(String),
+    /// A numeric ID.
     Id(u32),
+    /// Nothing set (don't change the user or group).
     #[serde(skip)]
     Nothing,
 }
@@ -270,26 +351,359 @@ impl Default for SecId {
     }
 }
 
-#[derive(Debug, Default, Deserialize, Eq, PartialEq)]
+/// An error returned when the user to switch to during daemonization doesn't exist.
+#[derive(Debug, Fail)]
+#[fail(display = "User {} not found", _0)]
+pub struct UnknownUser(String);
+
+/// An error returned when the group to switch to during daemonization doesn't exist.
+#[derive(Debug, Fail)]
+#[fail(display = "Group {} not found", _0)]
+pub struct UnknownGroup(String);
+
+impl SecId {
+    /// Resolves the user.
+    ///
+    /// Returns the uid to switch to together with the passwd entry, if there's one (a numeric ID
+    /// doesn't need to have an entry).
+    fn user(&self) -> Result<Option<(Uid, Option<User>)>, Error> {
+        match *self {
+            SecId::Id(id) => Ok(Some((Uid::from_raw(id), users::get_user_by_uid(id)))),
+            SecId::Name(ref name) => {
+                let user =
+                    users::get_user_by_name(name).ok_or_else(|| UnknownUser(name.clone()))?;
+                Ok(Some((Uid::from_raw(user.uid()), Some(user))))
+            }
+            SecId::Nothing => Ok(None),
+        }
+    }
+
+    fn group(&self) -> Result<Option<Gid>, Error> {
+        match *self {
+            SecId::Id(id) => Ok(Some(Gid::from_raw(id))),
+            SecId::Name(ref name) => {
+                let group =
+                    users::get_group_by_name(name).ok_or_else(|| UnknownGroup(name.clone()))?;
+                Ok(Some(Gid::from_raw(group.gid())))
+            }
+            SecId::Nothing => Ok(None),
+        }
+    }
+
+    /// Resolves the user ID.
+    ///
+    /// Returns `None` for `Nothing` and an error for a user name that doesn't exist.
+    pub fn uid(&self) -> Result<Option<u32>, Error> {
+        Ok(self.user()?.map(|(uid, _)| uid.into()))
+    }
+
+    /// Resolves the group ID.
+    ///
+    /// Returns `None` for `Nothing` and an error for a group name that doesn't exist.
+    pub fn gid(&self) -> Result<Option<u32>, Error> {
+        Ok(self.group()?.map(|gid| gid.into()))
+    }
+}
+
+fn is_nothing(id: &SecId) -> bool {
+    *id == SecId::Nothing
+}
+
+#[derive(Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename_all = "kebab-case")]
 struct Daemon {
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "is_nothing")]
     user: SecId,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "is_nothing")]
     group: SecId,
     pid_file: Option<PathBuf>,
     workdir: Option<PathBuf>,
+    shutdown_timeout_ms: Option<u64>,
+}
+
+/// The user and group to switch to, resolved from the configuration.
+#[derive(Debug)]
+struct Credentials {
+    uid: Option<Uid>,
+    gid: Option<Gid>,
+    /// The passwd entry of the user, if there's one.
+    passwd: Option<User>,
+}
+
+impl Daemon {
+    /// Resolves the user and group names.
+    ///
+    /// If no group is set, the primary group of the user is used, so we don't keep root's.
+    fn credentials(&self) -> Result<Credentials, Error> {
+        let (uid, passwd) = match self.user.user()? {
+            Some((uid, passwd)) => (Some(uid), passwd),
+            None => (None, None),
+        };
+        let gid = self.group.group()?.or_else(|| {
+            passwd
+                .as_ref()
+                .map(|passwd| Gid::from_raw(passwd.primary_group_id()))
+        });
+        Ok(Credentials { uid, gid, passwd })
+    }
+
+    /// Checks if the options that take effect only on startup are the same.
+    fn same_startup(&self, other: &Daemon) -> bool {
+        self.user == other.user
+            && self.group == other.group
+            && self.pid_file == other.pid_file
+            && self.workdir == other.workdir
+    }
 }
 
+/// The part of the configuration spirit itself takes care of.
 #[derive(Deserialize)]
-struct ConfigWrapper<C> {
-    #[serde(flatten)]
-    config: C,
+struct SpiritConfig {
     #[serde(default)]
     daemon: Daemon,
     #[serde(default)]
     logging: Vec<Logging>,
-    // TODO: Find a way to detect and report unused fields
+}
+
+struct ConfigWrapper<C> {
+    config: C,
+    daemon: Daemon,
+    logging: Vec<Logging>,
+    /// Paths of the configuration keys nothing used.
+    unused: Vec<String>,
+    sources: ConfigSources,
+}
+
+impl<C> ConfigWrapper<C>
+where
+    for<'de> C: Deserialize<'de>,
+{
+    /// Deserializes the configuration, noting the keys nothing used.
+    fn parse(config: Config, sources: ConfigSources) -> Result<Self, Error> {
+        let config: HashMap<String, Value> = config.try_into()?;
+        // Serde doesn't report unused keys through flatten, so our part of the configuration is
+        // deserialized separately. The user's structure still gets the whole of it, it may have
+        // its own `logging` or `daemon` fields. The split only decides which keys count as used.
+        const OURS: &[&str] = &["daemon", "logging"];
+        let ours = config
+            .iter()
+            .filter(|&(key, _)| OURS.contains(&key.as_str()))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<HashMap<_, _>>();
+        let ours_raw = Value::from(ours);
+        let raw = Value::from(config);
+        let mut ours_unused = Vec::new();
+        let (parsed, flattened) = validation::collect_unused(|| {
+            let mut note = |path: IgnoredPath| ours_unused.push(key_path(&path));
+            serde_ignored::deserialize::<_, _, SpiritConfig>(ours_raw.clone(), &mut note)
+        });
+        let ours = parsed?;
+        ours_unused.extend(locate_unused(&ours_raw, flattened));
+        for (i, logging) in ours.logging.iter().enumerate() {
+            let keys = logging.unused_keys();
+            ours_unused.extend(keys.into_iter().map(|key| format!("logging.{}.{}", i, key)));
+        }
+        let mut user_unused = Vec::new();
+        let (parsed, flattened) = validation::collect_unused(|| {
+            let mut note = |path: IgnoredPath| user_unused.push(key_path(&path));
+            serde_ignored::deserialize::<_, _, C>(raw.clone(), &mut note)
+        });
+        let config = parsed?;
+        user_unused.extend(locate_unused(&raw, flattened));
+        // A key in our part is unused only if neither we nor the user used it.
+        let covered = |paths: &[String], path: &str| paths.iter().any(|p| under(path, p));
+        let mut unused = user_unused
+            .iter()
+            .filter(|path| !OURS.iter().any(|key| under(path, key)) || covered(&ours_unused, path))
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in &ours_unused {
+            if covered(&user_unused, path) && !unused.contains(path) {
+                unused.push(path.clone());
+            }
+        }
+        Ok(ConfigWrapper {
+            config,
+            daemon: ours.daemon,
+            logging: ours.logging,
+            unused,
+            sources,
+        })
+    }
+}
+
+/// Formats the path of a configuration key, the same way as the config overrides take it.
+fn key_path(path: &IgnoredPath) -> String {
+    let (parent, key) = match *path {
+        IgnoredPath::Root => return String::new(),
+        IgnoredPath::Seq { parent, index } => (parent, index.to_string()),
+        IgnoredPath::Map { parent, ref key } => (parent, key.clone()),
+        IgnoredPath::Some { parent }
+        | IgnoredPath::NewtypeStruct { parent }
+        | IgnoredPath::NewtypeVariant { parent } => return key_path(parent),
+    };
+    join_key(&key_path(parent), &key)
+}
+
+fn join_key(parent: &str, key: &str) -> String {
+    if parent.is_empty() {
+        key.to_owned()
+    } else {
+        format!("{}.{}", parent, key)
+    }
+}
+
+/// Is the path the prefix or one of its sub-keys?
+fn under(path: &str, prefix: &str) -> bool {
+    path == prefix || path.starts_with(&format!("{}.", prefix))
+}
+
+/// Lists the paths of all the tables in the configuration, with their keys.
+fn tables(value: Value, path: String, out: &mut Vec<(String, HashSet<String>)>) {
+    if let Ok(table) = value.clone().into_table() {
+        let keys = table.keys().cloned().collect();
+        let mut table = table.into_iter().collect::<Vec<_>>();
+        table.sort_by(|a, b| a.0.cmp(&b.0));
+        for (key, value) in table {
+            let path = join_key(&path, &key);
+            tables(value, path, out);
+        }
+        out.push((path, keys));
+    } else if let Ok(array) = value.into_array() {
+        for (i, value) in array.into_iter().enumerate() {
+            tables(value, join_key(&path, &i.to_string()), out);
+        }
+    }
+}
+
+/// Finds the full paths of the keys reported by [`Unused`](validation/struct.Unused.html).
+///
+/// These come without the place they were found in, so each group is matched to the first table
+/// containing all of its keys that no other group took yet. If there's no such table, the keys are
+/// reported as they are.
+fn locate_unused(config: &Value, groups: Vec<Vec<String>>) -> Vec<String> {
+    let mut candidates = Vec::new();
+    tables(config.clone(), String::new(), &mut candidates);
+    let mut taken = vec![false; candidates.len()];
+    let mut result = Vec::new();
+    for group in groups {
+        let found = (0..candidates.len())
+            .find(|&i| !taken[i] && group.iter().all(|key| candidates[i].1.contains(key)));
+        match found {
+            Some(i) => {
+                taken[i] = true;
+                let parent = &candidates[i].0;
+                result.extend(group.iter().map(|key| join_key(parent, key)));
+            }
+            None => result.extend(group),
+        }
+    }
+    result
+}
+
+/// Where a configuration value came from.
+///
+/// See [`config_source`](struct.Spirit.html#method.config_source).
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum ConfigSource {
+    /// The [`config_defaults`](struct.Builder.html#method.config_defaults).
+    Defaults,
+    /// A configuration file, either passed directly or found in a directory.
+    File(PathBuf),
+    /// Environment variables with the given prefix (see
+    /// [`config_env`](struct.Builder.html#method.config_env)).
+    Env(String),
+    /// The `--config-override` command line option.
+    Override,
+}
+
+impl Display for ConfigSource {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            ConfigSource::Defaults => write!(fmt, "config defaults"),
+            ConfigSource::File(ref path) => write!(fmt, "{}", path.display()),
+            ConfigSource::Env(ref prefix) => write!(fmt, "{}_* environment variables", prefix),
+            ConfigSource::Override => write!(fmt, "command line override"),
+        }
+    }
+}
+
+type ConfigSources = HashMap<String, ConfigSource>;
+
+/// Turns a config path expression (`listen[0].port`) into the form we use (`listen.0.port`).
+fn normalize_key(key: &str) -> String {
+    key.replace('[', ".").replace(']', "")
+}
+
+/// Records that the value at the key came from the source.
+///
+/// Tables are merged with what was there before, anything else replaces the previous value
+/// (including whole arrays).
+fn record_source(sources: &mut ConfigSources, key: String, value: &Value, source: &ConfigSource) {
+    let children = format!("{}.", key);
+    let forget = |sources: &mut ConfigSources| sources.retain(|k, _| !k.starts_with(&children));
+    if let Ok(table) = value.clone().into_table() {
+        // A table replaces a scalar or an array, but merges with another table.
+        if sources.remove(&key).is_some() {
+            forget(sources);
+        }
+        for (sub, value) in &table {
+            record_source(sources, join_key(&key, sub), value, source);
+        }
+    } else {
+        forget(sources);
+        if let Ok(items) = value.clone().into_array() {
+            for (i, item) in items.iter().enumerate() {
+                record_source(sources, join_key(&key, &i.to_string()), item, source);
+            }
+        }
+        sources.insert(key, source.clone());
+    }
+}
+
+fn find_source<'a>(sources: &'a ConfigSources, key: &str) -> Option<&'a ConfigSource> {
+    let mut key = normalize_key(key);
+    loop {
+        if let Some(source) = sources.get(&key) {
+            return Some(source);
+        }
+        // A value inside something set as a whole (like an array) comes from the same place.
+        match key.rfind('.') {
+            Some(pos) => key.truncate(pos),
+            None => return None,
+        }
+    }
+}
+
+/// Already collected values from some configuration source.
+///
+/// We need to look at the values to know where each came from. Merging these instead of the
+/// original source avoids reading and parsing it twice.
+#[derive(Clone, Debug)]
+struct Collected(HashMap<String, Value>);
+
+impl Source for Collected {
+    fn clone_into_box(&self) -> Box<Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<HashMap<String, Value>, ConfigError> {
+        Ok(self.0.clone())
+    }
+}
+
+fn merge_source<S: Source>(
+    config: &mut Config,
+    sources: &mut ConfigSources,
+    source: &S,
+    from: ConfigSource,
+) -> Result<(), Error> {
+    let values = source.collect()?;
+    for (key, value) in &values {
+        record_source(sources, normalize_key(key), value, &from);
+    }
+    config.merge(Collected(values))?;
+    Ok(())
 }
 
 /// An error returned when the user passes a key-value option without equal sign.
@@ -313,6 +727,28 @@ where
     Ok((opt[..pos].parse()?, opt[pos + 1..].parse()?))
 }
 
+/// An error returned when the user asks for a configuration format we don't know.
+#[derive(Debug, Fail)]
+#[fail(display = "Unknown configuration format {}", _0)]
+pub struct UnknownFormat(String);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+impl FromStr for ConfigFormat {
+    type Err = UnknownFormat;
+    fn from_str(s: &str) -> Result<Self, UnknownFormat> {
+        match s {
+            "toml" => Ok(ConfigFormat::Toml),
+            "json" => Ok(ConfigFormat::Json),
+            _ => Err(UnknownFormat(s.to_owned())),
+        }
+    }
+}
+
 #[derive(Debug, StructOpt)]
 struct CommonOpts {
     /// Override specific config values.
@@ -336,6 +772,17 @@ struct CommonOpts {
     #[structopt(short = "l", long = "log", raw(number_of_values = "1"))]
     log: Option<LevelFilter>,
 
+    /// Print the merged configuration in the given format and exit.
+    #[structopt(
+        long = "dump-config",
+        raw(possible_values = r#"&["toml", "json"]"#, number_of_values = "1")
+    )]
+    dump_config: Option<ConfigFormat>,
+
+    /// Validate the configuration and exit.
+    #[structopt(long = "check-config")]
+    check_config: bool,
+
     /// Log to stderr with overriden levels for specific modules.
     #[structopt(
         short = "L",
@@ -409,6 +856,7 @@ struct Hooks<O, C> {
     config_filter: Box<FnMut(&Path) -> bool + Send>,
     config: Vec<Box<FnMut(&Arc<C>) + Send>>,
     config_validators: Vec<Box<FnMut(&Arc<C>, &mut C, &O) -> ValidationResults + Send>>,
+    reload: Vec<Box<FnMut(Reload) + Send>>,
     sigs: HashMap<libc::c_int, Vec<Box<FnMut() + Send>>>,
     terminate: Vec<Box<FnMut() + Send>>,
 }
@@ -420,12 +868,27 @@ impl<O, C> Default for Hooks<O, C> {
             config_filter: no_filter,
             config: Vec::new(),
             config_validators: Vec::new(),
+            reload: Vec::new(),
             sigs: HashMap::new(),
             terminate: Vec::new(),
         }
     }
 }
 
+/// A stage of a configuration reload.
+///
+/// This is passed to the [`on_reload`](struct.Builder.html#method.on_reload) callbacks.
+#[derive(Debug)]
+pub enum Reload<'a> {
+    /// The configuration is about to be reloaded.
+    Started,
+    /// The reload finished, either successfully or with an error.
+    ///
+    /// In case of an error, the old configuration stays active (unless it is the first one, in
+    /// which case the application doesn't start).
+    Finished(Result<(), &'a Error>),
+}
+
 /// The main manipulation handle/struct of the library.
 ///
 /// This gives access to the runtime control over the behaviour of the spirit library and allows
@@ -471,13 +934,22 @@ where
     hooks: Mutex<Hooks<O, C>>,
     // TODO: Mode selection for directories
     config_files: Vec<PathBuf>,
+    config_sources: Mutex<ConfigSources>,
     config_defaults: Option<String>,
     config_env: Option<String>,
     config_overrides: HashMap<String, String>,
     daemonize: bool,
     extra_logger: Option<Logging>,
     opts: O,
+    unused_keys: ValidationLevel,
     previous_daemon: Mutex<Option<Daemon>>,
+    /// How many times the service thread panicked.
+    service_panics: AtomicUsize,
+    /// Since when the service thread handles a signal, `None` while it waits for one.
+    service_busy: Mutex<Option<Instant>>,
+    shutdown_timeout: Mutex<Option<Duration>>,
+    stats: Mutex<stats::Reloads>,
+    running: Arc<Mutex<shutdown::Running>>,
     terminate: AtomicBool,
 }
 
@@ -533,8 +1005,12 @@ where
             config_env: None,
             config_hooks: Vec::new(),
             config_filter: Box::new(|_| false),
+            config_unused_keys: ValidationLevel::Warning,
             config_validators: Vec::new(),
+            config_watch: false,
+            log_reopen_signal: None,
             opts: PhantomData,
+            reload_hooks: Vec::new(),
             sig_hooks: HashMap::new(),
             singletons: HashSet::new(),
             terminate_hooks: Vec::new(),
@@ -585,10 +1061,37 @@ where
         self.config.borrow().lease()
     }
 
+    /// Finds out where a value in the current configuration came from.
+    ///
+    /// The key is a path to the value, like `listen.0.port` (or `listen[0].port`). Values inside
+    /// something that was set as a whole (like an array) come from the same place as the whole.
+    /// Returns `None` if there's no such value in the configuration.
+    ///
+    /// Validators can attach the key to their results with
+    /// [`validation::Result::key`](validation/struct.Result.html#method.key) and spirit adds the
+    /// source to the message.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use spirit::{ConfigSource, Empty, Spirit};
+    ///
+    /// let (spirit, _, _) = Spirit::<_, Empty, _>::new(Empty {})
+    ///     .config_defaults("[daemon]\nworkdir = \"/\"")
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(Some(ConfigSource::Defaults), spirit.config_source("daemon.workdir"));
+    /// assert_eq!(None, spirit.config_source("daemon.pid-file"));
+    /// ```
+    pub fn config_source(&self, key: &str) -> Option<ConfigSource> {
+        find_source(&self.config_sources.lock(), key).cloned()
+    }
+
     fn daemonize(&self, daemon: &Daemon) -> Result<(), Error> {
-        // TODO: Discovering by name
-        // TODO: Tests for this
         debug!("Preparing to daemonize with {:?}", daemon);
+        // Resolve the names first, while we still have stderr to complain to.
+        let Credentials { uid, gid, passwd } = daemon.credentials()?;
         stat::umask(Mode::empty()); // No restrictions on write modes
         let workdir = daemon
             .workdir
@@ -627,15 +1130,30 @@ where
                 .open(file)?;
             writeln!(f, "{}", unistd::getpid())?;
         }
-        match daemon.group {
-            SecId::Id(id) => unistd::setgid(Gid::from_raw(id))?,
-            SecId::Name(_) => unimplemented!("Discovering group name"),
-            SecId::Nothing => (),
+        // The supplementary groups can be changed only by root and must be done before we drop the
+        // privileges.
+        if uid.is_some() && unistd::geteuid().is_root() {
+            match (passwd, gid) {
+                (Some(passwd), Some(gid)) => {
+                    trace!("Setting supplementary groups of {:?}", passwd.name());
+                    let name = CString::new(passwd.name().as_bytes())?;
+                    unistd::initgroups(&name, gid)?;
+                }
+                // Not in the database, so there's nothing to set, but don't keep the ones of root.
+                (_, gid) => {
+                    trace!("Clearing supplementary groups");
+                    let groups = gid.into_iter().collect::<Vec<_>>();
+                    unistd::setgroups(&groups)?;
+                }
+            }
+        }
+        if let Some(gid) = gid {
+            trace!("Switching to group {}", gid);
+            unistd::setgid(gid)?;
         }
-        match daemon.user {
-            SecId::Id(id) => unistd::setuid(Uid::from_raw(id))?,
-            SecId::Name(_) => unimplemented!("Discovering user name"),
-            SecId::Nothing => (),
+        if let Some(uid) = uid {
+            trace!("Switching to user {}", uid);
+            unistd::setuid(uid)?;
         }
         Ok(())
     }
@@ -654,7 +1172,11 @@ where
     /// * The configuration is published into the storage.
     /// * The `on_config` callbacks are called.
     ///
-    /// If any step fails, it is aborted and the old configuration is preserved.
+    /// If any step fails, it is aborted and the old configuration is preserved. The
+    /// [`on_reload`](struct.Builder.html#method.on_reload) callbacks are called before the first
+    /// step and after the last one (or the failed one).
+    ///
+    /// Once the application [terminates](#method.terminate), the reloads are ignored.
     ///
     /// # Warning
     ///
@@ -663,7 +1185,37 @@ where
     /// don't have to by `Sync`). That, however, means that you can't call `config_reload` or
     /// [`terminate`](#method.terminate) from any callback (that would lead to a deadlock).
     pub fn config_reload(&self) -> Result<(), Error> {
+        if self.is_terminated() {
+            debug!("Not reloading configuration during shutdown");
+            return Ok(());
+        }
+        for hook in &mut self.hooks.lock().reload {
+            hook(Reload::Started);
+        }
+        let result = self.reload();
+        {
+            let mut stats = self.stats.lock();
+            if result.is_ok() {
+                stats.reloads += 1;
+                stats.last_reload = Some(SystemTime::now());
+            } else {
+                stats.reload_failures += 1;
+                stats.last_reload_failure = Some(SystemTime::now());
+            }
+        }
+        for hook in &mut self.hooks.lock().reload {
+            hook(Reload::Finished(result.as_ref().map(|_| ())));
+        }
+        result
+    }
+
+    fn reload(&self) -> Result<(), Error> {
         let config = self.load_config()?;
+        if self.previous_daemon.lock().is_none() {
+            // Check the user and group exist before switching the logging, so the error is visible.
+            config.daemon.user.user()?;
+            config.daemon.group.group()?;
+        }
         // The lock here is across the whole processing, to avoid potential races in logic
         // processing. This makes writing the hooks correctly easier.
         let mut hooks = self.hooks.lock();
@@ -672,22 +1224,17 @@ where
         debug!("Creating new logging");
         // Prepare the logger first, but don't switch until we know we use the new config.
         let loggers = logging::create(config.logging.iter().chain(&self.extra_logger))?;
-        debug!("Running config validators");
-        let mut results = hooks
-            .config_validators
-            .iter_mut()
-            .map(|v| v(&old, &mut new, &self.opts))
-            .fold(ValidationResults::new(), |mut acc, r| {
-                acc.merge(r);
-                acc
-            });
+        let mut results =
+            self.validate(&mut hooks, &old, &mut new, &config.unused, &config.sources);
         let new = Arc::new(new);
         for result in &results {
             match result.level() {
                 ValidationLevel::Error => {
+                    self.stats.lock().validation_errors += 1;
                     error!(target: "configuration", "{}", result.description());
                 }
                 ValidationLevel::Warning => {
+                    self.stats.lock().validation_warnings += 1;
                     warn!(target: "configuration", "{}", result.description());
                 }
                 ValidationLevel::Hint => {
@@ -698,11 +1245,7 @@ where
         }
         if results.max_level() == Some(ValidationLevel::Error) {
             error!(target: "configuration", "Refusing new configuration due to errors");
-            for r in &mut results.0 {
-                if let Some(abort) = r.on_abort.as_mut() {
-                    abort();
-                }
-            }
+            results.abort();
             return Err(ValidationError.into());
         }
         debug!("Validation successful, installing new config");
@@ -716,14 +1259,17 @@ where
         logging::install(loggers);
         // And to the new config.
         self.config.borrow().store(Arc::clone(&new));
+        *self.config_sources.lock() = config.sources;
         debug!("Running post-configuration hooks");
         for hook in &mut hooks.config {
             hook(&new);
         }
         debug!("Configuration reloaded");
+        *self.shutdown_timeout.lock() =
+            config.daemon.shutdown_timeout_ms.map(Duration::from_millis);
         let mut daemon = self.previous_daemon.lock();
         if let Some(ref daemon) = *daemon {
-            if daemon != &config.daemon {
+            if !daemon.same_startup(&config.daemon) {
                 warn!("Can't change daemon configuration at runtime");
             }
         } else {
@@ -733,6 +1279,119 @@ where
         Ok(())
     }
 
+    fn validate(
+        &self,
+        hooks: &mut Hooks<O, C>,
+        old: &Arc<C>,
+        new: &mut C,
+        unused: &[String],
+        sources: &ConfigSources,
+    ) -> ValidationResults {
+        debug!("Running config validators");
+        let level = self.unused_keys;
+        let unused = unused
+            .iter()
+            .map(|key| {
+                ValidationResult::new(level, format!("Unused configuration key {}", key)).key(key)
+            }).collect::<Vec<_>>();
+        let mut results = hooks
+            .config_validators
+            .iter_mut()
+            .map(|v| v(old, new, &self.opts))
+            .fold(ValidationResults::from(unused), |mut acc, r| {
+                acc.merge(r);
+                acc
+            });
+        for result in &mut results.0 {
+            let source = result
+                .key
+                .as_ref()
+                .and_then(|key| find_source(sources, key));
+            if let Some(source) = source {
+                result.set_in(source);
+            }
+        }
+        results
+    }
+
+    /// Loads the configuration and runs the validators on it, without using it.
+    ///
+    /// The results are printed to the standard output. Fails if the configuration contains
+    /// errors.
+    fn check_config(&self) -> Result<(), Error> {
+        let config = self.load_config()?;
+        config.daemon.user.user()?;
+        config.daemon.group.group()?;
+        let mut hooks = self.hooks.lock();
+        let old = self.config.borrow().load();
+        let (mut new, unused, sources) = (config.config, config.unused, config.sources);
+        let mut results =
+            validation::checking(|| self.validate(&mut hooks, &old, &mut new, &unused, &sources));
+        for result in &results {
+            if result.level() != ValidationLevel::Nothing {
+                println!("{}: {}", result.level(), result.description());
+            }
+        }
+        // The configuration is only checked, never used, so whatever the validators prepared
+        // needs to be rolled back.
+        results.abort();
+        if results.max_level() == Some(ValidationLevel::Error) {
+            return Err(ValidationError.into());
+        }
+        Ok(())
+    }
+
+    /// Registers something the shutdown waits for, under the given name.
+    ///
+    /// It stays registered until the returned guard is dropped. If the shutdown doesn't finish
+    /// before the deadline, the names of everything still registered are logged (see the
+    /// [`shutdown`](shutdown/index.html) module).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::thread;
+    ///
+    /// use spirit::{Empty, Spirit};
+    ///
+    /// Spirit::<_, Empty, _>::new(Empty {})
+    ///     .run(|spirit| {
+    ///         let pending = spirit.pending("worker thread");
+    ///         let worker = thread::spawn(move || {
+    ///             // Do the work here
+    ///             drop(pending);
+    ///         });
+    ///         # spirit.terminate();
+    ///         worker.join().unwrap();
+    ///         Ok(())
+    ///     });
+    /// ```
+    pub fn pending<N: Into<String>>(&self, name: N) -> shutdown::Pending {
+        shutdown::Pending::new(&self.running, name.into())
+    }
+
+    /// Returns a snapshot of the statistics about spirit itself.
+    ///
+    /// See the [`stats`](stats/index.html) module.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use spirit::{Empty, Spirit};
+    ///
+    /// let (spirit, _, _) = Spirit::<_, Empty, _>::new(Empty {})
+    ///     .build()
+    ///     .unwrap();
+    /// # spirit.terminate();
+    ///
+    /// let stats = spirit.stats();
+    /// assert_eq!(1, stats.reloads);
+    /// assert_eq!(0, stats.reload_failures);
+    /// ```
+    pub fn stats(&self) -> stats::Stats {
+        stats::Stats::new(&self.stats.lock())
+    }
+
     /// Is the application in the shutdown phase?
     ///
     /// This can be used if the daemon does some kind of periodic work, every loop it can check if
@@ -769,17 +1428,26 @@ where
     ///
     /// The termination does this:
     ///
+    /// * Starts the shutdown deadline, if `shutdown-timeout-ms` is configured (see the
+    ///   [`shutdown`](shutdown/index.html) module).
     /// * Calls the `on_terminate` callbacks.
     /// * Sets the [`is_terminated`](#method.is_terminated) flag is set.
     /// * Drops all callbacks from spirit. This allows destruction/termination of parts of program
     ///   by dropping remote handles or similar things.
-    /// * The background thread terminates.
+    ///
+    /// Another termination signal after this exits the application right away. Configuration
+    /// reloads (`SIGHUP`) are ignored from then on.
     ///
     /// # Warning
     ///
     /// The Spirit guarantees only one callback runs at a time. That means you can't call this from
     /// within a callback (it would lead to deadlock).
     pub fn terminate(&self) {
+        if !self.is_terminated() {
+            if let Some(timeout) = *self.shutdown_timeout.lock() {
+                shutdown::deadline(timeout, &self.running);
+            }
+        }
         debug!("Running termination hooks");
         for hook in &mut self.hooks.lock().terminate {
             hook();
@@ -790,49 +1458,103 @@ where
 
     fn background(&self, signals: &Signals) {
         debug!("Starting background processing");
+        // Only the signals count, the application may have terminated itself already and the
+        // first signal still shouldn't cut the shutdown short.
+        let mut terminations = 0;
+        // In case we got restarted after a panic in the middle of handling something.
+        *self.service_busy.lock() = None;
         for signal in signals.forever() {
             debug!("Received signal {}", signal);
-            let term = match signal {
+            *self.service_busy.lock() = Some(Instant::now());
+            match signal {
                 libc::SIGHUP => {
                     let _ = log_errors(|| self.config_reload());
-                    false
+                }
+                libc::SIGTERM | libc::SIGINT | libc::SIGQUIT if terminations > 0 => {
+                    error!("Terminated again during shutdown, exiting right away");
+                    process::exit(1);
                 }
                 libc::SIGTERM | libc::SIGINT | libc::SIGQUIT => {
+                    terminations += 1;
                     self.terminate();
-                    true
                 }
                 // Some other signal, only for the hook benefit
-                _ => false,
-            };
+                _ => (),
+            }
 
             if let Some(hooks) = self.hooks.lock().sigs.get_mut(&signal) {
                 for hook in hooks {
                     hook();
                 }
             }
-
-            if term {
-                debug!("Terminating the background thread");
-                return;
-            }
+            *self.service_busy.lock() = None;
         }
         unreachable!("Signals run forever");
     }
 
+    /// Prints the configuration as it would be used.
+    ///
+    /// Our own sections are shown after deserialization, with all their defaults filled in. The
+    /// application's part is shown as merged from the sources, since we can't serialize `C` ‒ but
+    /// it is still deserialized to check it's usable.
+    fn dump_config(&self, format: ConfigFormat) -> Result<(), Error> {
+        let (config, sources) = self.load_config_raw()?;
+        let parsed = ConfigWrapper::<C>::parse(config.clone(), sources)?;
+        let dumped = match format {
+            ConfigFormat::Toml => {
+                let mut dump = config.try_into::<toml::value::Table>()?;
+                dump.insert("daemon".to_owned(), toml::Value::try_from(&parsed.daemon)?);
+                dump.insert(
+                    "logging".to_owned(),
+                    toml::Value::try_from(&parsed.logging)?,
+                );
+                toml::to_string_pretty(&toml::Value::Table(dump))?
+            }
+            ConfigFormat::Json => {
+                let mut dump = config.try_into::<serde_json::Map<_, _>>()?;
+                dump.insert("daemon".to_owned(), serde_json::to_value(&parsed.daemon)?);
+                dump.insert("logging".to_owned(), serde_json::to_value(&parsed.logging)?);
+                serde_json::to_string_pretty(&dump)?
+            }
+        };
+        println!("{}", dumped);
+        Ok(())
+    }
+
+    /// Finishes the building in a mode where the application isn't supposed to run.
+    ///
+    /// The spirit is marked as terminated and the bodies do nothing.
+    fn done(self) -> (Arc<Self>, InnerBody, WrapBody) {
+        self.terminate.store(true, Ordering::Relaxed);
+        let inner = InnerBody(Box::new(Some(|()| Ok(()))));
+        let wrapped = WrapBody(Box::new(Some(|_: InnerBody| Ok(()))));
+        (Arc::new(self), inner, wrapped)
+    }
+
     fn load_config(&self) -> Result<ConfigWrapper<C>, Error> {
+        let (config, sources) = self.load_config_raw()?;
+        ConfigWrapper::parse(config, sources)
+    }
+
+    /// Loads and merges all the configuration sources, without deserializing the result.
+    fn load_config_raw(&self) -> Result<(Config, ConfigSources), Error> {
         debug!("Loading configuration");
         let mut config = Config::new();
+        let mut sources = ConfigSources::new();
         // To avoid problems with trying to parse without any configuration present (it would
         // complain that it found unit and whatever the config was is expected instead).
         config.merge(File::from_str("", FileFormat::Toml))?;
         if let Some(ref defaults) = self.config_defaults {
             trace!("Loading config defaults");
-            config.merge(File::from_str(defaults, FileFormat::Toml))?;
+            let defaults = File::from_str(defaults, FileFormat::Toml);
+            merge_source(&mut config, &mut sources, &defaults, ConfigSource::Defaults)?;
         }
         for path in &self.config_files {
             if path.is_file() {
                 trace!("Loading config file {:?}", path);
-                config.merge(File::from(path as &Path))?;
+                let file = File::from(path as &Path);
+                let source = ConfigSource::File(path.clone());
+                merge_source(&mut config, &mut sources, &file, source)?;
             } else if path.is_dir() {
                 trace!("Scanning directory {:?}", path);
                 let mut lock = self.hooks.lock();
@@ -855,7 +1577,8 @@ where
                 files.sort();
                 for file in files {
                     trace!("Loading config file {:?}", file);
-                    config.merge(File::from(file))?;
+                    let source = ConfigSource::File(file.clone());
+                    merge_source(&mut config, &mut sources, &File::from(file), source)?;
                 }
             } else {
                 bail!(InvalidFileType(path.to_owned()));
@@ -863,13 +1586,18 @@ where
         }
         if let Some(env_prefix) = self.config_env.as_ref() {
             trace!("Loading config from environment {}", env_prefix);
-            config.merge(Environment::with_prefix(env_prefix))?;
+            let env = Environment::with_prefix(env_prefix);
+            let source = ConfigSource::Env(env_prefix.clone());
+            merge_source(&mut config, &mut sources, &env, source)?;
         }
         for (ref key, ref value) in &self.config_overrides {
             trace!("Config override {} => {}", key, value);
             config.set(*key, *value as &str)?;
+            let value = Value::from(*value as &str);
+            let key = normalize_key(key);
+            record_source(&mut sources, key, &value, &ConfigSource::Override);
         }
-        Ok(config.try_into()?)
+        Ok((config, sources))
     }
 }
 
@@ -935,6 +1663,10 @@ where
     config_hooks: Vec<Box<FnMut(&Arc<C>) + Send>>,
     config_filter: Box<FnMut(&Path) -> bool + Send>,
     config_validators: Vec<Box<FnMut(&Arc<C>, &mut C, &O) -> ValidationResults + Send>>,
+    config_unused_keys: ValidationLevel,
+    reload_hooks: Vec<Box<FnMut(Reload) + Send>>,
+    config_watch: bool,
+    log_reopen_signal: Option<libc::c_int>,
     opts: PhantomData<O>,
     sig_hooks: HashMap<libc::c_int, Vec<Box<FnMut() + Send>>>,
     singletons: HashSet<TypeId>,
@@ -1044,17 +1776,28 @@ where
     ///
     /// The two latter ones are often set by helpers, so you should not ignore them.
     ///
+    /// If the user asked only to dump or check the configuration, this is done here and the
+    /// returned spirit is already terminated (an invalid configuration is returned as an error). The bodies do nothing in such case, so the application ends
+    /// without running.
+    ///
     /// # Warning
     ///
     /// If asked to go to background, this uses `fork`. Therefore, start any threads after you call
     /// `build` (or from within [`run`](#method.run)), or you'll lose them ‒ only the thread doing
     /// fork is preserved across it.
     // TODO: The new return value
-    pub fn build(self) -> Result<(Arc<Spirit<S, O, C>>, InnerBody, WrapBody), Error> {
+    pub fn build(mut self) -> Result<(Arc<Spirit<S, O, C>>, InnerBody, WrapBody), Error> {
+        if let Some(signal) = self.log_reopen_signal {
+            self = self.on_signal(signal, logfile::reopen);
+        }
         let mut logger = Logging {
-            destination: LogDestination::StdErr,
+            destination: LogDestination::StdErr {
+                coloring: Coloring::default(),
+            },
             level: LevelFilter::Warn,
             per_module: HashMap::new(),
+            formatting: Formatting::default(),
+            rest: HashMap::new(),
         };
         log_reroute::init()?;
         logging::install(logging::create(iter::once(&logger)).unwrap());
@@ -1071,21 +1814,32 @@ where
         } else {
             opts.common.configs
         };
+        // We change the working directory during daemonization, so we need to be able to find the
+        // files on reload.
+        let cwd = env::current_dir()?;
+        let config_files = config_files.into_iter().map(|f| cwd.join(f)).collect();
         let interesting_signals = self
             .sig_hooks
             .keys()
             .chain(&[libc::SIGHUP, libc::SIGTERM, libc::SIGQUIT, libc::SIGINT])
             .cloned()
             .collect::<HashSet<_>>(); // Eliminate duplicates
+        let dump_config = opts.common.dump_config;
+        let check_config = opts.common.check_config;
         let log_modules = opts.common.log_modules;
         let extra_logger = opts.common.log.map(|level| Logging {
-            destination: LogDestination::StdErr,
+            destination: LogDestination::StdErr {
+                coloring: Coloring::default(),
+            },
             level,
             per_module: log_modules.into_iter().collect(),
+            formatting: Formatting::default(),
+            rest: HashMap::new(),
         });
         let spirit = Spirit {
             config: self.config,
             config_files,
+            config_sources: Mutex::new(ConfigSources::new()),
             config_defaults: self.config_defaults,
             config_env: self.config_env,
             config_overrides: opts.common.config_overrides.into_iter().collect(),
@@ -1095,32 +1849,49 @@ where
                 config: self.config_hooks,
                 config_filter: self.config_filter,
                 config_validators: self.config_validators,
+                reload: self.reload_hooks,
                 sigs: self.sig_hooks,
                 terminate: self.terminate_hooks,
             }),
             opts: opts.other,
             previous_daemon: Mutex::new(None),
+            service_panics: AtomicUsize::new(0),
+            service_busy: Mutex::new(None),
+            shutdown_timeout: Mutex::new(None),
+            stats: Mutex::new(stats::Reloads::default()),
+            running: Arc::new(Mutex::new(shutdown::Running::default())),
+            unused_keys: self.config_unused_keys,
             terminate: AtomicBool::new(false),
         };
+        if let Some(format) = dump_config {
+            spirit.dump_config(format)?;
+            return Ok(spirit.done());
+        }
+        if check_config {
+            spirit.check_config()?;
+            return Ok(spirit.done());
+        }
         spirit.config_reload()?;
         let signals = Signals::new(interesting_signals)?;
         let spirit = Arc::new(spirit);
+        if self.config_watch {
+            watch::start(&spirit)?;
+        }
         let spirit_bg = Arc::clone(&spirit);
         thread::Builder::new()
             .name("spirit".to_owned())
             .spawn(move || {
                 loop {
                     // Note: we run a bunch of callbacks inside the service thread. We restart the
-                    // thread if it fails.
+                    // thread if it fails. It keeps running even after termination, to handle
+                    // a repeated termination signal.
                     let run = AssertUnwindSafe(|| spirit_bg.background(&signals));
-                    if panic::catch_unwind(run).is_err() {
-                        // FIXME: Something better than this to prevent looping?
-                        thread::sleep(Duration::from_secs(1));
-                        info!("Restarting the spirit service thread after a panic");
-                    } else {
-                        // Willingly terminated
-                        break;
-                    }
+                    // The background runs forever, so this can only be a panic.
+                    let _ = panic::catch_unwind(run);
+                    spirit_bg.service_panics.fetch_add(1, Ordering::Relaxed);
+                    // FIXME: Something better than this to prevent looping?
+                    thread::sleep(Duration::from_secs(1));
+                    info!("Restarting the spirit service thread after a panic");
                 }
             }).unwrap(); // Could fail only if the name contained \0
         debug!(
@@ -1323,6 +2094,67 @@ where
         }
     }
 
+    /// Enables reloading the configuration when the files change.
+    ///
+    /// Normally, the configuration is reloaded on `SIGHUP` (or when someone calls
+    /// [`config_reload`](struct.Spirit.html#method.config_reload)). If this is turned on, the
+    /// configuration files and directories are also watched and the configuration is reloaded
+    /// once they change, are created or removed (the changes are debounced, so a bunch of changes
+    /// in quick succession results in only one reload).
+    ///
+    /// This works with files that are replaced by swapping a symlink somewhere up the path (as it
+    /// happens, for example, with config maps in kubernetes), not only modified in place.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use spirit::{Empty, Spirit};
+    ///
+    /// Spirit::<_, Empty, _>::new(Empty {})
+    ///     .config_exts(&["toml", "ini", "json"])
+    ///     .config_watch()
+    ///     .run(|_spirit| {
+    ///         // The application runs here, configuration reloads happen in the background
+    ///         Ok(())
+    ///     });
+    /// ```
+    pub fn config_watch(self) -> Self {
+        Self {
+            config_watch: true,
+            ..self
+        }
+    }
+
+    /// Sets how to treat configuration keys nothing used.
+    ///
+    /// Keys that are present in the configuration, but nothing reads them, are usually typos (or
+    /// leftovers from older versions) and the value silently doesn't take effect. These are
+    /// reported as [validation results](validation/struct.Result.html) of the given level. The
+    /// default is `Warning`, setting it to `Error` refuses such configuration and `Nothing` turns
+    /// the reporting off.
+    ///
+    /// Keys inside structures with `#[serde(flatten)]` fields can be detected only with the help
+    /// of [`Unused`](validation/struct.Unused.html).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use spirit::{Empty, Spirit};
+    /// use spirit::validation::Level;
+    ///
+    /// Spirit::<_, Empty, _>::new(Empty {})
+    ///     .config_unused_keys(Level::Error)
+    ///     .run(|_spirit| {
+    ///         Ok(())
+    ///     });
+    /// ```
+    pub fn config_unused_keys(self, level: ValidationLevel) -> Self {
+        Self {
+            config_unused_keys: level,
+            ..self
+        }
+    }
+
     /// Adds another config validator to the chain.
     ///
     /// The validators are there to check, possibly modify and possibly refuse a newly loaded
@@ -1390,6 +2222,74 @@ where
         }
     }
 
+    /// Adds a callback notified when the configuration is being reloaded.
+    ///
+    /// It is called with [`Reload::Started`](enum.Reload.html) before each attempt to reload the
+    /// configuration (including the first load) and with
+    /// [`Reload::Finished`](enum.Reload.html) once the attempt ends, successful or not.
+    ///
+    /// Unlike [`on_config`](#method.on_config), this allows reacting to failed reloads too.
+    ///
+    /// TODO: Threads, deadlocks
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use spirit::{Empty, Reload, Spirit};
+    ///
+    /// Spirit::<_, Empty, _>::new(Empty {})
+    ///     .on_reload(|reload| match reload {
+    ///         Reload::Started => println!("Reloading"),
+    ///         Reload::Finished(Ok(())) => println!("Reloaded"),
+    ///         Reload::Finished(Err(e)) => println!("Reload failed: {}", e),
+    ///     })
+    ///     .run(|_spirit| {
+    ///         Ok(())
+    ///     });
+    /// ```
+    pub fn on_reload<F: FnMut(Reload) + Send + 'static>(self, hook: F) -> Self {
+        let mut hooks = self.reload_hooks;
+        hooks.push(Box::new(hook));
+        Self {
+            reload_hooks: hooks,
+            ..self
+        }
+    }
+
+    /// Sets the signal to reopen the log files on.
+    ///
+    /// When a log file is moved away (for example by logrotate), the application keeps writing
+    /// into the old one until it reopens it. This happens on a configuration reload (`SIGHUP`), but
+    /// that also re-validates and re-applies the whole configuration. This signal only makes the
+    /// `file` logging destinations reopen their files, before writing the next message.
+    ///
+    /// Not set by default, as the application may use the signal for something else. `SIGUSR1`
+    /// is the usual choice.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// extern crate libc;
+    /// extern crate spirit;
+    ///
+    /// use spirit::{Empty, Spirit};
+    ///
+    /// fn main() {
+    ///     Spirit::<_, Empty, _>::new(Empty {})
+    ///         .log_reopen_signal(Some(libc::SIGUSR1))
+    ///         .run(|_spirit| {
+    ///             // The application runs here
+    ///             Ok(())
+    ///         });
+    /// }
+    /// ```
+    pub fn log_reopen_signal(self, signal: Option<libc::c_int>) -> Self {
+        Self {
+            log_reopen_signal: signal,
+            ..self
+        }
+    }
+
     /// Adds a callback for reacting to a signal.
     ///
     /// The [`Spirit`](struct.Spirit.html) reacts to some signals itself, in its own service
@@ -1430,3 +2330,203 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use validation::Unused;
+
+    fn parse<C>(config: &str) -> ConfigWrapper<C>
+    where
+        for<'de> C: Deserialize<'de>,
+    {
+        let mut merged = Config::new();
+        merged
+            .merge(File::from_str(config, FileFormat::Toml))
+            .unwrap();
+        ConfigWrapper::parse(merged, ConfigSources::new()).unwrap()
+    }
+
+    #[test]
+    fn unused_keys() {
+        let config = parse::<Empty>(
+            r#"
+            typo = 1
+            [[logging]]
+            type = "stderr"
+            level = "WARN"
+            bogus = true
+            "#,
+        );
+        assert_eq!(1, config.logging.len());
+        let mut unused = config.unused;
+        unused.sort();
+        assert_eq!(vec!["logging.0.bogus", "typo"], unused);
+    }
+
+    #[test]
+    fn unused_destination_keys() {
+        let config = parse::<Empty>(
+            r#"
+            [[logging]]
+            type = "file"
+            filename = "/tmp/log"
+            rotate-size = 1024
+            keep = 2
+            copytruncate = true
+            level = "WARN"
+            format = "json"
+            color = "always"
+            [[logging]]
+            type = "stdout"
+            level = "WARN"
+            color = "always"
+            color-target = true
+            filename = "/tmp/log"
+            "#,
+        );
+        let mut unused = config.unused;
+        unused.sort();
+        assert_eq!(vec!["logging.0.color", "logging.1.filename"], unused);
+    }
+
+    #[test]
+    fn config_sources() {
+        let mut config = Config::new();
+        let mut sources = ConfigSources::new();
+        let mut merge = |content: &str, source: ConfigSource| {
+            let file = File::from_str(content, FileFormat::Toml);
+            merge_source(&mut config, &mut sources, &file, source).unwrap();
+        };
+        let (first, second) = (PathBuf::from("first.toml"), PathBuf::from("second.toml"));
+        merge(
+            "[ui]\nmessage = \"hello\"\ncolor = \"red\"\nlisten = [1, 2]",
+            ConfigSource::Defaults,
+        );
+        merge("[ui]\ncolor = \"blue\"", ConfigSource::File(first.clone()));
+        merge("[ui]\nlisten = [3]", ConfigSource::File(second.clone()));
+        record_source(
+            &mut sources,
+            normalize_key("ui.message"),
+            &Value::from("hi"),
+            &ConfigSource::Override,
+        );
+        let source = |key| find_source(&sources, key).cloned();
+        assert_eq!(Some(ConfigSource::Override), source("ui.message"));
+        assert_eq!(Some(ConfigSource::File(first)), source("ui.color"));
+        // The whole array is replaced, including its items
+        assert_eq!(Some(ConfigSource::File(second.clone())), source("ui.listen"));
+        assert_eq!(Some(ConfigSource::File(second)), source("ui.listen[0]"));
+        assert!(!sources.contains_key("ui.listen.1"));
+        assert_eq!(None, source("ui.nothing"));
+    }
+
+    #[test]
+    fn unknown_user() {
+        let daemon = Daemon {
+            user: SecId::Name("spirit-no-such-user".to_owned()),
+            ..Daemon::default()
+        };
+        let err = daemon.credentials().unwrap_err();
+        assert!(err.downcast_ref::<UnknownUser>().is_some());
+        let daemon = Daemon {
+            group: SecId::Name("spirit-no-such-group".to_owned()),
+            ..Daemon::default()
+        };
+        let err = daemon.credentials().unwrap_err();
+        assert!(err.downcast_ref::<UnknownGroup>().is_some());
+    }
+
+    #[test]
+    fn root() {
+        for user in &[SecId::Name("root".to_owned()), SecId::Id(0)] {
+            let daemon = Daemon {
+                user: user.clone(),
+                ..Daemon::default()
+            };
+            let credentials = daemon.credentials().unwrap();
+            assert_eq!(Some(Uid::from_raw(0)), credentials.uid);
+            // The primary group of the user
+            assert_eq!(Some(Gid::from_raw(0)), credentials.gid);
+            assert_eq!("root", credentials.passwd.unwrap().name());
+        }
+        let daemon = Daemon {
+            user: SecId::Name("root".to_owned()),
+            group: SecId::Id(12345),
+            ..Daemon::default()
+        };
+        let credentials = daemon.credentials().unwrap();
+        assert_eq!(Some(Gid::from_raw(12345)), credentials.gid);
+        let credentials = Daemon::default().credentials().unwrap();
+        assert!(credentials.uid.is_none());
+        assert!(credentials.gid.is_none());
+    }
+
+    #[derive(Deserialize)]
+    struct OwnLogging {
+        logging: Vec<BTreeMap<String, String>>,
+    }
+
+    #[test]
+    fn user_shares_our_keys() {
+        let config = parse::<OwnLogging>(
+            r#"
+            [[logging]]
+            type = "stderr"
+            level = "INFO"
+            "#,
+        );
+        assert_eq!("INFO", config.config.logging[0]["level"]);
+        assert_eq!(1, config.logging.len());
+        assert!(config.unused.is_empty());
+    }
+
+    #[derive(Deserialize)]
+    struct Common {
+        #[serde(default)]
+        name: String,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Deserialize)]
+    struct Service {
+        #[serde(flatten)]
+        common: Common,
+        #[serde(default)]
+        port: u16,
+        #[serde(flatten)]
+        unused: Unused,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Deserialize)]
+    struct Services {
+        #[serde(default)]
+        listen: Vec<Service>,
+        #[serde(flatten)]
+        unused: Unused,
+    }
+
+    #[test]
+    fn flattened_unused_paths() {
+        let config = parse::<Services>(
+            r#"
+            [daemon]
+            workdir = "/"
+            [[listen]]
+            name = "first"
+            port = 1234
+            [[listen]]
+            name = "second"
+            tpyo = 42
+            [[logging]]
+            type = "stderr"
+            level = "WARN"
+            "#,
+        );
+        assert_eq!("second", config.config.listen[1].common.name);
+        assert_eq!(vec!["listen.1.tpyo"], config.unused);
+    }
+}
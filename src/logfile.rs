@@ -0,0 +1,417 @@
+//! Log files with rotation.
+//!
+//! The rotated files are numbered, `.1` being the newest one. When a file is rotated, the older
+//! ones are shifted by one and the ones over the `keep` limit are deleted. The compression happens
+//! in a background thread, so it doesn't block the logging. The next rotation of the same file
+//! waits for it to finish, even if it's done by a new `LogFile` after a configuration reload.
+//!
+//! For rotation done by someone else (logrotate), the files can be asked to
+//! [`reopen`](fn.reopen.html) and, in the `copytruncate` mode, they notice when they got
+//! truncated.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Error as IoError, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Duration as ChronoDuration, Local, NaiveDateTime, Timelike};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use parking_lot::Mutex;
+
+/// After a failed rotation, don't try again for this long.
+const RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Bumped each time the files are asked to reopen.
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    /// The compression threads, by the path of the log file they belong to.
+    static ref COMPRESSING: Mutex<HashMap<PathBuf, JoinHandle<()>>> = Mutex::new(HashMap::new());
+}
+
+/// Waits for the compression of the file's last rotated version, if there's any running.
+fn wait_compression(path: &Path) {
+    let handle = COMPRESSING.lock().remove(path);
+    if let Some(handle) = handle {
+        let _ = handle.join();
+    }
+}
+
+/// Makes all the log files reopen before writing the next line.
+///
+/// Unlike a configuration reload, this keeps the loggers as they are.
+pub(crate) fn reopen() {
+    debug!("Reopening log files");
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Interval {
+    Hourly,
+    Daily,
+}
+
+impl Interval {
+    /// The start of the period after the one the time belongs to.
+    fn next(self, time: NaiveDateTime) -> NaiveDateTime {
+        match self {
+            Interval::Hourly => {
+                let start = time.date().and_hms_opt(time.hour(), 0, 0).unwrap();
+                start + ChronoDuration::hours(1)
+            }
+            Interval::Daily => {
+                let start = time.date().and_hms_opt(0, 0, 0).unwrap();
+                start + ChronoDuration::days(1)
+            }
+        }
+    }
+}
+
+fn default_keep() -> usize {
+    5
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct Rotate {
+    #[serde(rename = "rotate-size")]
+    size: Option<u64>,
+    #[serde(rename = "rotate-interval")]
+    interval: Option<Interval>,
+    #[serde(default = "default_keep")]
+    keep: usize,
+    #[serde(default)]
+    compress: bool,
+}
+
+fn open(path: &Path) -> Result<File, IoError> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn compress(path: &Path, target: &Path) -> Result<(), IoError> {
+    let mut input = File::open(path)?;
+    let mut output = GzEncoder::new(File::create(target)?, Compression::default());
+    io::copy(&mut input, &mut output)?;
+    output.finish()?;
+    fs::remove_file(path)
+}
+
+fn ignore_missing(result: Result<(), IoError>) -> Result<(), IoError> {
+    match result {
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        other => other,
+    }
+}
+
+/// A log file, rotated as configured.
+///
+/// The rotation happens only between whole lines, never in the middle of a log message.
+pub(crate) struct LogFile {
+    path: PathBuf,
+    file: File,
+    rotate: Rotate,
+    copytruncate: bool,
+    generation: usize,
+    size: u64,
+    next_rotation: Option<NaiveDateTime>,
+    at_line_start: bool,
+    retry_after: Option<Instant>,
+}
+
+impl LogFile {
+    pub(crate) fn open(path: &Path, rotate: &Rotate, copytruncate: bool) -> Result<Self, IoError> {
+        let generation = GENERATION.load(Ordering::Relaxed);
+        let file = open(path)?;
+        let metadata = file.metadata()?;
+        // Continue the period the existing file was last written in, so it is rotated right away
+        // if that one is already over (for example, the application was not running at midnight).
+        let modified = metadata
+            .modified()
+            .map(|time| DateTime::<Local>::from(time).naive_local())
+            .unwrap_or_else(|_| Local::now().naive_local());
+        Ok(LogFile {
+            path: path.to_owned(),
+            file,
+            rotate: rotate.clone(),
+            copytruncate,
+            generation,
+            size: metadata.len(),
+            next_rotation: rotate.interval.map(|interval| interval.next(modified)),
+            at_line_start: true,
+            retry_after: None,
+        })
+    }
+
+    fn rotated(&self, num: usize, ext: &str) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}{}", num, ext));
+        name.into()
+    }
+
+    fn reopen(&mut self) -> Result<(), IoError> {
+        self.file = open(&self.path)?;
+        self.size = self.file.metadata()?.len();
+        Ok(())
+    }
+
+    /// Checks if the file got moved away (we were asked to reopen) or truncated under our hands.
+    fn maybe_reopen(&mut self) -> Result<(), IoError> {
+        let generation = GENERATION.load(Ordering::Relaxed);
+        if generation != self.generation {
+            self.generation = generation;
+            self.reopen()
+        } else if self.copytruncate && self.file.metadata()?.len() < self.size {
+            self.reopen()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn should_rotate(&self) -> bool {
+        let now = Instant::now();
+        match self.retry_after {
+            Some(retry) if retry > now => return false,
+            _ => (),
+        }
+        let too_big = match self.rotate.size {
+            Some(max) => self.size > 0 && self.size >= max,
+            None => false,
+        };
+        let too_old = match self.next_rotation {
+            Some(next) => Local::now().naive_local() >= next,
+            None => false,
+        };
+        too_big || too_old
+    }
+
+    fn rotate(&mut self) -> Result<(), IoError> {
+        // The previous compression works with the .1 file, which is about to be moved.
+        wait_compression(&self.path);
+        let keep = self.rotate.keep;
+        for num in (1..=keep).rev() {
+            // Both forms, in case the compression got turned on or off or failed.
+            for ext in &["", ".gz"] {
+                let old = self.rotated(num, ext);
+                if num == keep {
+                    ignore_missing(fs::remove_file(&old))?;
+                } else {
+                    ignore_missing(fs::rename(&old, self.rotated(num + 1, ext)))?;
+                }
+            }
+        }
+        let rotated = self.rotated(1, "");
+        if keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            fs::rename(&self.path, &rotated)?;
+        }
+        self.file = open(&self.path)?;
+        self.size = 0;
+        self.next_rotation = self
+            .rotate
+            .interval
+            .map(|interval| interval.next(Local::now().naive_local()));
+        if self.rotate.compress && keep > 0 {
+            let target = self.rotated(1, ".gz");
+            let handle = thread::Builder::new()
+                .name("spirit-log-compress".to_owned())
+                .spawn(move || {
+                    if let Err(e) = compress(&rotated, &target) {
+                        error!("Failed to compress log file {}: {}", rotated.display(), e);
+                    }
+                })?;
+            COMPRESSING.lock().insert(self.path.clone(), handle);
+        }
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        if self.at_line_start {
+            self.maybe_reopen()?;
+        }
+        if self.at_line_start && self.should_rotate() {
+            if let Err(e) = self.rotate() {
+                self.retry_after = Some(Instant::now() + RETRY_AFTER);
+                // Make sure we still write somewhere, even if the rename happened but the
+                // reopening failed.
+                if let Ok(file) = open(&self.path) {
+                    self.size = file.metadata().map(|m| m.len()).unwrap_or(0);
+                    self.file = file;
+                }
+                let msg = format!("Failed to rotate log file {}: {}", self.path.display(), e);
+                return Err(IoError::new(e.kind(), msg));
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        if written > 0 {
+            self.at_line_start = buf[written - 1] == b'\n';
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io::Read;
+    use std::process;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("spirit-logfile-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn read(&self, name: &str) -> String {
+            fs::read_to_string(self.0.join(name)).unwrap()
+        }
+
+        fn exists(&self, name: &str) -> bool {
+            self.0.join(name).exists()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn rotate(size: u64, keep: usize, compress: bool) -> Rotate {
+        Rotate {
+            size: Some(size),
+            interval: None,
+            keep,
+            compress,
+        }
+    }
+
+    #[test]
+    fn size() {
+        let dir = TempDir::new("size");
+        let path = dir.0.join("log");
+        let mut file = LogFile::open(&path, &rotate(5, 2, false), false).unwrap();
+        // Not rotated in the middle of a line, even when over the limit
+        file.write_all(b"first line").unwrap();
+        file.write_all(b"\n").unwrap();
+        assert_eq!("first line\n", dir.read("log"));
+        for line in &["second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        assert_eq!("fourth\n", dir.read("log"));
+        assert_eq!("third\n", dir.read("log.1"));
+        assert_eq!("second\n", dir.read("log.2"));
+        assert!(!dir.exists("log.3"));
+    }
+
+    #[test]
+    fn keep_none() {
+        let dir = TempDir::new("keep-none");
+        let path = dir.0.join("log");
+        let mut file = LogFile::open(&path, &rotate(5, 0, true), false).unwrap();
+        file.write_all(b"first line\n").unwrap();
+        file.write_all(b"second line\n").unwrap();
+        assert_eq!("second line\n", dir.read("log"));
+        assert!(!dir.exists("log.1"));
+        assert!(!dir.exists("log.1.gz"));
+    }
+
+    fn gunzip(dir: &TempDir, name: &str) -> String {
+        let mut content = String::new();
+        let file = File::open(dir.0.join(name)).unwrap();
+        GzDecoder::new(file).read_to_string(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn compressed() {
+        let dir = TempDir::new("compressed");
+        let path = dir.0.join("log");
+        let mut file = LogFile::open(&path, &rotate(5, 3, true), false).unwrap();
+        for line in &["first\n", "second\n", "third\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        wait_compression(&path);
+        assert_eq!("third\n", dir.read("log"));
+        assert_eq!("second\n", gunzip(&dir, "log.1.gz"));
+        assert_eq!("first\n", gunzip(&dir, "log.2.gz"));
+        assert!(!dir.exists("log.1"));
+        assert!(!dir.exists("log.2"));
+
+        // A new instance (as after a reload) keeps shifting them, including the uncompressed ones
+        // from before the compression got turned on.
+        fs::rename(dir.0.join("log.2.gz"), dir.0.join("log.2")).unwrap();
+        drop(file);
+        let mut file = LogFile::open(&path, &rotate(5, 3, true), false).unwrap();
+        file.write_all(b"fourth\n").unwrap();
+        wait_compression(&path);
+        assert_eq!("fourth\n", dir.read("log"));
+        assert_eq!("third\n", gunzip(&dir, "log.1.gz"));
+        assert_eq!("second\n", gunzip(&dir, "log.2.gz"));
+        assert!(dir.exists("log.3"));
+        assert!(!dir.exists("log.4"));
+        assert!(!dir.exists("log.4.gz"));
+    }
+
+    #[test]
+    fn retry() {
+        let dir = TempDir::new("retry");
+        let path = dir.0.join("log");
+        let mut file = LogFile::open(&path, &rotate(5, 1, false), false).unwrap();
+        file.write_all(b"first\n").unwrap();
+        // Something the rotated file can't replace
+        fs::create_dir(dir.0.join("log.1")).unwrap();
+        assert!(file.write_all(b"second\n").is_err());
+        // It doesn't try again right away, the logging goes on in the same file
+        file.write_all(b"second\n").unwrap();
+        assert_eq!("first\nsecond\n", dir.read("log"));
+
+        fs::remove_dir(dir.0.join("log.1")).unwrap();
+        file.retry_after = Some(Instant::now());
+        file.write_all(b"third\n").unwrap();
+        assert_eq!("third\n", dir.read("log"));
+        assert_eq!("first\nsecond\n", dir.read("log.1"));
+    }
+
+    #[test]
+    fn reopen_moved() {
+        let dir = TempDir::new("reopen");
+        let path = dir.0.join("log");
+        let no_rotate = Rotate {
+            size: None,
+            interval: None,
+            keep: 0,
+            compress: false,
+        };
+        let mut file = LogFile::open(&path, &no_rotate, false).unwrap();
+        file.write_all(b"first\n").unwrap();
+        // Moved away by someone else, we keep writing into it
+        fs::rename(&path, dir.0.join("log.old")).unwrap();
+        file.write_all(b"second\n").unwrap();
+        assert!(!dir.exists("log"));
+
+        reopen();
+        file.write_all(b"third\n").unwrap();
+        assert_eq!("first\nsecond\n", dir.read("log.old"));
+        assert_eq!("third\n", dir.read("log"));
+    }
+}
@@ -0,0 +1,558 @@
+//! Formatting of the log messages.
+//!
+//! The text format is driven by a template with `{placeholder}`s, the default one is built from the
+//! `thread` and `pid` options. The JSON format has a fixed set of fields.
+//!
+//! When writing to a terminal, the level (and optionally the target) of the text format can be
+//! colored.
+
+use std::fmt::{Arguments, Write};
+use std::os::unix::io::RawFd;
+use std::process;
+use std::thread;
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local, SecondsFormat, TimeZone, Utc};
+use failure::Error;
+use fern::Dispatch;
+use log::{Level, Record};
+use nix::unistd;
+use serde_json;
+
+const DEFAULT_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3f";
+
+/// This error is returned when a log template or time format is invalid.
+#[derive(Debug, Fail)]
+#[fail(display = "Invalid log format {}: {}", _0, _1)]
+pub struct LogFormatError(String, &'static str);
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Timezone {
+    Local,
+    Utc,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Color {
+    Auto,
+    Always,
+    Never,
+}
+
+fn default_color() -> Color {
+    Color::Auto
+}
+
+/// The color options of the stdout and stderr destinations.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct Coloring {
+    #[serde(default = "default_color")]
+    color: Color,
+    #[serde(rename = "color-target", default)]
+    target: bool,
+}
+
+impl Default for Coloring {
+    fn default() -> Self {
+        Coloring {
+            color: Color::Auto,
+            target: false,
+        }
+    }
+}
+
+impl Coloring {
+    /// What to color when writing into the given file descriptor.
+    pub(crate) fn colors(&self, fd: RawFd) -> Colors {
+        let enabled = match self.color {
+            Color::Auto => unistd::isatty(fd).unwrap_or(false),
+            Color::Always => true,
+            Color::Never => false,
+        };
+        Colors {
+            level: enabled,
+            target: enabled && self.target,
+        }
+    }
+}
+
+/// What parts of the messages are colored.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Colors {
+    level: bool,
+    target: bool,
+}
+
+const RESET: &str = "\x1b[0m";
+const TARGET_COLOR: &str = "\x1b[36m";
+
+fn level_color(level: Level) -> &'static str {
+    match level {
+        Level::Error => "\x1b[31m",
+        Level::Warn => "\x1b[33m",
+        Level::Info => "\x1b[32m",
+        Level::Debug => "\x1b[34m",
+        Level::Trace => "\x1b[35m",
+    }
+}
+
+fn default_format() -> Format {
+    Format::Text
+}
+
+fn default_timezone() -> Timezone {
+    Timezone::Local
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum TimeFormat {
+    Rfc3339,
+    Strftime(String),
+}
+
+impl TimeFormat {
+    fn parse(format: &str) -> Result<Self, LogFormatError> {
+        if format == "rfc3339" {
+            Ok(TimeFormat::Rfc3339)
+        } else if StrftimeItems::new(format).any(|item| item == Item::Error) {
+            Err(LogFormatError(format.to_owned(), "invalid time format"))
+        } else {
+            Ok(TimeFormat::Strftime(format.to_owned()))
+        }
+    }
+
+    fn format<Tz: TimeZone>(&self, time: &DateTime<Tz>, utc: bool) -> String
+    where
+        Tz::Offset: ::std::fmt::Display,
+    {
+        match *self {
+            TimeFormat::Rfc3339 => time.to_rfc3339_opts(SecondsFormat::Millis, utc),
+            TimeFormat::Strftime(ref format) => time.format(format).to_string(),
+        }
+    }
+}
+
+fn now(timezone: Timezone, format: &TimeFormat) -> String {
+    match timezone {
+        Timezone::Local => format.format(&Local::now(), false),
+        Timezone::Utc => format.format(&Utc::now(), true),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Field {
+    Level,
+    Target,
+    Module,
+    File,
+    Line,
+    Thread,
+    Pid,
+    Message,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Piece {
+    Literal(String),
+    /// The time, either in the given format or in the one of the whole logger.
+    Time(Option<TimeFormat>),
+    /// A field, padded to the given width.
+    Field(Field, usize),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Template(Vec<Piece>);
+
+impl Template {
+    fn parse(template: &str) -> Result<Self, LogFormatError> {
+        let err = |msg| LogFormatError(template.to_owned(), msg);
+        let mut pieces = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '}' => return Err(err("unmatched }")),
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => return Err(err("unmatched {")),
+                        }
+                    }
+                    let mut parts = placeholder.splitn(2, ':');
+                    let name = parts.next().unwrap_or("");
+                    let spec = parts.next();
+                    let field = match name {
+                        "time" => None,
+                        "level" => Some(Field::Level),
+                        "target" => Some(Field::Target),
+                        "module" => Some(Field::Module),
+                        "file" => Some(Field::File),
+                        "line" => Some(Field::Line),
+                        "thread" => Some(Field::Thread),
+                        "pid" => Some(Field::Pid),
+                        "message" => Some(Field::Message),
+                        _ => return Err(err("unknown placeholder")),
+                    };
+                    if !literal.is_empty() {
+                        pieces.push(Piece::Literal(literal.split_off(0)));
+                    }
+                    let piece = match (field, spec) {
+                        (None, None) => Piece::Time(None),
+                        (None, Some(format)) => Piece::Time(Some(TimeFormat::parse(format)?)),
+                        (Some(field), None) => Piece::Field(field, 0),
+                        (Some(field), Some(width)) => {
+                            let width = width.parse().map_err(|_| err("invalid width"))?;
+                            Piece::Field(field, width)
+                        }
+                    };
+                    pieces.push(piece);
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            pieces.push(Piece::Literal(literal));
+        }
+        Ok(Template(pieces))
+    }
+
+    fn render(
+        &self,
+        timezone: Timezone,
+        time_format: &TimeFormat,
+        colors: Colors,
+        message: &Arguments,
+        record: &Record,
+    ) -> String {
+        let mut out = String::new();
+        for piece in &self.0 {
+            let _ = match *piece {
+                Piece::Literal(ref literal) => out.write_str(literal),
+                Piece::Time(ref format) => {
+                    let time = now(timezone, format.as_ref().unwrap_or(time_format));
+                    out.write_str(&time)
+                }
+                Piece::Field(field, width) => {
+                    let thread = thread::current();
+                    let value = match field {
+                        Field::Level => record.level().to_string(),
+                        Field::Target => record.target().to_owned(),
+                        Field::Module => record.module_path().unwrap_or("").to_owned(),
+                        Field::File => record.file().unwrap_or("").to_owned(),
+                        Field::Line => record.line().map(|l| l.to_string()).unwrap_or_default(),
+                        Field::Thread => thread.name().unwrap_or("<unnamed>").to_owned(),
+                        Field::Pid => process::id().to_string(),
+                        Field::Message => message.to_string(),
+                    };
+                    let color = match field {
+                        Field::Level if colors.level => Some(level_color(record.level())),
+                        Field::Target if colors.target => Some(TARGET_COLOR),
+                        _ => None,
+                    };
+                    // The padding is on the visible text, without the escape sequences
+                    match color {
+                        Some(color) => {
+                            write!(out, "{}{:width$}{}", color, value, RESET, width = width)
+                        }
+                        None => write!(out, "{:width$}", value, width = width),
+                    }
+                }
+            };
+        }
+        out
+    }
+}
+
+/// One log message, as written by the JSON format.
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    timestamp: String,
+    level: String,
+    target: &'a str,
+    module: Option<&'a str>,
+    file: Option<&'a str>,
+    line: Option<u32>,
+    thread: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pid: Option<u32>,
+    message: String,
+}
+
+fn json(timezone: Timezone, pid: bool, message: &Arguments, record: &Record) -> String {
+    let thread = thread::current();
+    let record = JsonRecord {
+        timestamp: now(timezone, &TimeFormat::Rfc3339),
+        level: record.level().to_string(),
+        target: record.target(),
+        module: record.module_path(),
+        file: record.file(),
+        line: record.line(),
+        thread: thread.name(),
+        pid: if pid { Some(process::id()) } else { None },
+        message: message.to_string(),
+    };
+    serde_json::to_string(&record).expect("Log records are always serializable")
+}
+
+/// The formatting options of one logging destination.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Formatting {
+    #[serde(default = "default_format")]
+    format: Format,
+    template: Option<String>,
+    #[serde(default = "default_timezone")]
+    timezone: Timezone,
+    time_format: Option<String>,
+    #[serde(default)]
+    thread: bool,
+    #[serde(default)]
+    pid: bool,
+}
+
+impl Default for Formatting {
+    fn default() -> Self {
+        Formatting {
+            format: Format::Text,
+            template: None,
+            timezone: Timezone::Local,
+            time_format: None,
+            thread: false,
+            pid: false,
+        }
+    }
+}
+
+impl Formatting {
+    fn template(&self) -> String {
+        if let Some(ref template) = self.template {
+            return template.clone();
+        }
+        let mut template = "{time} ".to_owned();
+        if self.pid {
+            template.push_str("{pid} ");
+        }
+        if self.thread {
+            template.push_str("{thread:15} ");
+        }
+        template.push_str("{level:5} {target:30} {message}");
+        template
+    }
+
+    /// Sets up the formatting of the dispatch.
+    ///
+    /// The colors are used only by the text format.
+    pub(crate) fn apply(&self, dispatch: Dispatch, colors: Colors) -> Result<Dispatch, Error> {
+        let timezone = self.timezone;
+        match self.format {
+            Format::Text => {
+                let template = Template::parse(&self.template())?;
+                let time_format = self
+                    .time_format
+                    .as_ref()
+                    .map(|format| TimeFormat::parse(format))
+                    .unwrap_or_else(|| Ok(TimeFormat::Strftime(DEFAULT_TIME_FORMAT.to_owned())))?;
+                Ok(dispatch.format(move |out, message, record| {
+                    let line = template.render(timezone, &time_format, colors, message, record);
+                    out.finish(format_args!("{}", line))
+                }))
+            }
+            Format::Json => {
+                let pid = self.pid;
+                Ok(dispatch.format(move |out, message, record| {
+                    out.finish(format_args!("{}", json(timezone, pid, message, record)))
+                }))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::mpsc;
+
+    use chrono::NaiveDate;
+    use log::Level;
+    use serde_json::Value;
+
+    use super::*;
+
+    fn parse_err(template: &str) -> &'static str {
+        Template::parse(template).unwrap_err().1
+    }
+
+    #[test]
+    fn escapes() {
+        let template = Template::parse("{{{level}}}").unwrap();
+        let expected = vec![
+            Piece::Literal("{".to_owned()),
+            Piece::Field(Field::Level, 0),
+            Piece::Literal("}".to_owned()),
+        ];
+        assert_eq!(expected, template.0);
+    }
+
+    #[test]
+    fn invalid_templates() {
+        assert_eq!("unmatched {", parse_err("{level"));
+        assert_eq!("unmatched {", parse_err("{message} {"));
+        assert_eq!("unmatched }", parse_err("level}"));
+        assert_eq!("unknown placeholder", parse_err("{levle}"));
+        assert_eq!("unknown placeholder", parse_err("{}"));
+        assert_eq!("invalid width", parse_err("{level:wide}"));
+        assert_eq!("invalid width", parse_err("{level:-5}"));
+        assert_eq!("invalid time format", parse_err("{time:%Q}"));
+    }
+
+    #[test]
+    fn time_formats() {
+        let template = Template::parse("{time:%H:%M:%S%.3f}").unwrap();
+        let format = TimeFormat::Strftime("%H:%M:%S%.3f".to_owned());
+        assert_eq!(vec![Piece::Time(Some(format))], template.0);
+        assert_eq!(TimeFormat::Rfc3339, TimeFormat::parse("rfc3339").unwrap());
+        let time = NaiveDate::from_ymd_opt(2018, 1, 2)
+            .unwrap()
+            .and_hms_milli_opt(3, 4, 5, 678)
+            .unwrap();
+        let time = DateTime::<Utc>::from_naive_utc_and_offset(time, Utc);
+        let rfc = TimeFormat::Rfc3339.format(&time, true);
+        assert_eq!("2018-01-02T03:04:05.678Z", rfc);
+        let default = TimeFormat::parse(DEFAULT_TIME_FORMAT).unwrap();
+        assert_eq!("2018-01-02 03:04:05.678", default.format(&time, true));
+    }
+
+    #[test]
+    fn default_template() {
+        let template = Template::parse(&Formatting::default().template()).unwrap();
+        let expected = vec![
+            Piece::Time(None),
+            Piece::Literal(" ".to_owned()),
+            Piece::Field(Field::Level, 5),
+            Piece::Literal(" ".to_owned()),
+            Piece::Field(Field::Target, 30),
+            Piece::Literal(" ".to_owned()),
+            Piece::Field(Field::Message, 0),
+        ];
+        assert_eq!(expected, template.0);
+    }
+
+    #[test]
+    fn render() {
+        let template = Template::parse("[{level:5}] {target}:{line} {message}").unwrap();
+        let format = TimeFormat::Rfc3339;
+        let message = format_args!("Hello {}", "world");
+        let record = Record::builder()
+            .level(Level::Info)
+            .target("app")
+            .line(Some(42))
+            .args(message)
+            .build();
+        let colors = Colors::default();
+        let line = template.render(Timezone::Utc, &format, colors, &message, &record);
+        assert_eq!("[INFO ] app:42 Hello world", line);
+        let colors = Colors {
+            level: true,
+            target: false,
+        };
+        let line = template.render(Timezone::Utc, &format, colors, &message, &record);
+        assert_eq!("[\x1b[32mINFO \x1b[0m] app:42 Hello world", line);
+    }
+
+    /// Logs the record through a dispatch set up by the formatting, returns the output.
+    fn log_line(formatting: &Formatting, colors: Colors, record: &Record) -> String {
+        let (sender, receiver) = mpsc::channel();
+        let dispatch = formatting.apply(Dispatch::new(), colors).unwrap();
+        let (_, logger) = dispatch.chain(sender).into_log();
+        logger.log(record);
+        receiver.try_recv().unwrap()
+    }
+
+    #[test]
+    fn json_record() {
+        let message = format_args!("Hello \"{}\"\n", "world");
+        let record = Record::builder()
+            .level(Level::Warn)
+            .target("app")
+            .module_path(Some("app::net"))
+            .file(Some("src/net.rs"))
+            .line(Some(42))
+            .args(message)
+            .build();
+        let line = json(Timezone::Utc, true, &message, &record);
+        let parsed: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!("WARN", parsed["level"]);
+        assert_eq!("app", parsed["target"]);
+        assert_eq!("app::net", parsed["module"]);
+        assert_eq!("src/net.rs", parsed["file"]);
+        assert_eq!(42, parsed["line"]);
+        assert_eq!(thread::current().name(), parsed["thread"].as_str());
+        assert_eq!(process::id() as u64, parsed["pid"]);
+        assert_eq!("Hello \"world\"\n", parsed["message"]);
+        let timestamp = parsed["timestamp"].as_str().unwrap();
+        assert!(DateTime::parse_from_rfc3339(timestamp).is_ok());
+
+        let line = json(Timezone::Local, false, &message, &record);
+        let parsed: Value = serde_json::from_str(&line).unwrap();
+        assert!(parsed.get("pid").is_none());
+    }
+
+    #[test]
+    fn coloring() {
+        let coloring = |color, target| Coloring { color, target };
+        let null = File::open("/dev/null").unwrap();
+        let fd = null.as_raw_fd();
+        let all = Colors {
+            level: true,
+            target: true,
+        };
+        assert_eq!(all, coloring(Color::Always, true).colors(fd));
+        let level = Colors {
+            level: true,
+            target: false,
+        };
+        assert_eq!(level, coloring(Color::Always, false).colors(fd));
+        assert_eq!(Colors::default(), coloring(Color::Never, true).colors(fd));
+        // Not a terminal
+        assert_eq!(Colors::default(), coloring(Color::Auto, true).colors(fd));
+
+        let message = format_args!("Hello");
+        let record = Record::builder()
+            .level(Level::Error)
+            .target("app")
+            .args(message)
+            .build();
+        let text = Formatting::default();
+        assert!(log_line(&text, all, &record).contains("\x1b["));
+        assert!(!log_line(&text, Colors::default(), &record).contains("\x1b["));
+        let json = Formatting {
+            format: Format::Json,
+            ..Formatting::default()
+        };
+        let line = log_line(&json, all, &record);
+        assert!(!line.contains("\x1b["));
+        assert!(!line.contains("\\u001b"));
+        serde_json::from_str::<Value>(&line).unwrap();
+    }
+}
@@ -3,21 +3,30 @@ use std::io::{self, Write};
 use std::net::TcpStream;
 use std::path::PathBuf;
 
-use chrono::Local;
 use failure::Error;
-use fern::{self, Dispatch};
+use fern::Dispatch;
 use itertools::Itertools;
+use libc::{STDERR_FILENO, STDOUT_FILENO};
 use log::{self, LevelFilter, Log};
 use log_reroute;
-use serde::de::{Deserialize, Deserializer, Error as DeError};
+use serde::de::{Deserialize, Deserializer, Error as DeError, IgnoredAny};
+use serde::Serializer;
+use serde_json::{self, Value as JsonValue};
 use syslog;
 
-#[derive(Deserialize)]
-#[serde(tag = "type", rename_all = "kebab-case")] // TODO: Make deny-unknown-fields work
+use logfile::{LogFile, Rotate};
+use logformat::{Coloring, Colors, Formatting};
+use stats::Counting;
+
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
 pub(crate) enum LogDestination {
     File {
         filename: PathBuf,
-        // TODO: Truncate
+        #[serde(flatten)]
+        rotate: Rotate,
+        #[serde(default)]
+        copytruncate: bool,
     },
     Syslog {
         host: Option<String>,
@@ -28,9 +37,15 @@ pub(crate) enum LogDestination {
         port: u16,
     },
     #[serde(rename = "stdout")]
-    StdOut, // TODO: Colors
+    StdOut {
+        #[serde(flatten)]
+        coloring: Coloring,
+    },
     #[serde(rename = "stderr")]
-    StdErr, // TODO: Colors
+    StdErr {
+        #[serde(flatten)]
+        coloring: Coloring,
+    },
 }
 
 fn deserialize_level_filter<'de, D: Deserializer<'de>>(d: D) -> Result<LevelFilter, D::Error> {
@@ -54,24 +69,70 @@ where
         }).collect()
 }
 
+fn serialize_level_filter<S: Serializer>(level: &LevelFilter, s: S) -> Result<S::Ok, S::Error> {
+    s.collect_str(level)
+}
+
+fn serialize_per_module<S>(levels: &HashMap<String, LevelFilter>, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    s.collect_map(
+        levels
+            .iter()
+            .map(|(module, level)| (module, level.to_string())),
+    )
+}
+
 /// This error can be returned when initialization of logging to syslog fails.
 #[derive(Debug, Fail)]
 #[fail(display = "{}", _0)]
 pub struct SyslogError(String);
 
-#[derive(Deserialize)]
-#[serde(rename_all = "kebab-case")] // TODO: Make deny-unknown-fields work
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub(crate) struct Logging {
     #[serde(flatten)]
     pub(crate) destination: LogDestination,
-    #[serde(deserialize_with = "deserialize_level_filter")]
+    #[serde(
+        deserialize_with = "deserialize_level_filter",
+        serialize_with = "serialize_level_filter"
+    )]
     pub(crate) level: LevelFilter,
-    #[serde(default, deserialize_with = "deserialize_per_module")]
+    #[serde(
+        default,
+        deserialize_with = "deserialize_per_module",
+        serialize_with = "serialize_per_module"
+    )]
     pub(crate) per_module: HashMap<String, LevelFilter>,
-    // TODO: Format
+    #[serde(flatten)]
+    pub(crate) formatting: Formatting,
+    /// Everything not taken by the fields above, including the destination's keys.
+    #[serde(flatten, skip_serializing)]
+    pub(crate) rest: HashMap<String, IgnoredAny>,
 }
 
 impl Logging {
+    /// The configuration keys nothing used.
+    ///
+    /// Serde can't tell us which keys the flattened destination (an internally tagged enum) took.
+    /// So the used keys are the ones this serializes into, which serde derives from the same field
+    /// names as for the deserialization.
+    pub(crate) fn unused_keys(&self) -> Vec<&str> {
+        let used = match serde_json::to_value(self).expect("Logging is always serializable") {
+            JsonValue::Object(used) => used,
+            _ => unreachable!("Logging serializes into a map"),
+        };
+        let mut unused = self
+            .rest
+            .keys()
+            .map(|key| key.as_str())
+            .filter(|key| !used.contains_key(*key))
+            .collect::<Vec<_>>();
+        unused.sort();
+        unused
+    }
+
     pub(crate) fn create(&self) -> Result<Dispatch, Error> {
         let mut logger = Dispatch::new().level(self.level);
         logger = self
@@ -83,21 +144,25 @@ impl Logging {
         match self.destination {
             // We don't want to format syslog
             LogDestination::Syslog { .. } => (),
-            // We do with the other things
-            _ => {
-                logger = logger.format(|out, message, record| {
-                    out.finish(format_args!(
-                        "{} {:5} {:30} {}",
-                        Local::now().format("%Y-%m-%d %H:%M:%S:%.3f"),
-                        record.level(),
-                        record.target(),
-                        message,
-                    ))
-                });
+            // Only the terminals get colors
+            LogDestination::StdOut { ref coloring } => {
+                logger = self.formatting.apply(logger, coloring.colors(STDOUT_FILENO))?;
+            }
+            LogDestination::StdErr { ref coloring } => {
+                logger = self.formatting.apply(logger, coloring.colors(STDERR_FILENO))?;
             }
+            // We do with the other things
+            _ => logger = self.formatting.apply(logger, Colors::default())?,
         }
         match self.destination {
-            LogDestination::File { ref filename } => Ok(logger.chain(fern::log_file(filename)?)),
+            LogDestination::File {
+                ref filename,
+                ref rotate,
+                copytruncate,
+            } => {
+                let file = LogFile::open(filename, rotate, copytruncate)?;
+                Ok(logger.chain(Box::new(file) as Box<Write + Send>))
+            }
             LogDestination::Syslog { ref host } => {
                 let formatter = syslog::Formatter3164 {
                     facility: syslog::Facility::LOG_USER,
@@ -115,8 +180,8 @@ impl Logging {
                 let conn = TcpStream::connect((&host as &str, port))?;
                 Ok(logger.chain(Box::new(conn) as Box<Write + Send>))
             }
-            LogDestination::StdOut => Ok(logger.chain(io::stdout())),
-            LogDestination::StdErr => Ok(logger.chain(io::stderr())),
+            LogDestination::StdOut { .. } => Ok(logger.chain(io::stdout())),
+            LogDestination::StdErr { .. } => Ok(logger.chain(io::stderr())),
         }
     }
 }
@@ -134,6 +199,6 @@ where
 }
 
 pub(crate) fn install((max_log_level, top_logger): (LevelFilter, Box<Log>)) {
-    log_reroute::reroute_boxed(top_logger);
+    log_reroute::reroute_boxed(Box::new(Counting(top_logger)));
     log::set_max_level(max_log_level);
 }
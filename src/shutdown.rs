@@ -0,0 +1,84 @@
+//! Coordinated shutdown of the application.
+//!
+//! When the application is [terminated](../struct.Spirit.html#method.terminate), the parts of it
+//! are expected to wind down on their own (for example, the tokio helpers stop accepting new
+//! connections and let the runtime run empty). To make sure a stuck part doesn't keep the
+//! application alive forever, the `shutdown-timeout-ms` option of the `daemon` section sets a
+//! deadline. If the application is still running after it, the things still registered as
+//! [`Pending`](struct.Pending.html) are logged and the process exits with a failure.
+//!
+//! A second termination signal exits the application right away, without waiting. Terminating
+//! from within the application doesn't count as the first one.
+
+use std::collections::BTreeMap;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+
+#[derive(Default)]
+pub(crate) struct Running {
+    next: usize,
+    names: BTreeMap<usize, String>,
+}
+
+impl Running {
+    fn names(&self) -> String {
+        if self.names.is_empty() {
+            "nothing registered".to_owned()
+        } else {
+            self.names.values().cloned().collect::<Vec<_>>().join(", ")
+        }
+    }
+}
+
+/// Marks something the application waits for during shutdown.
+///
+/// It is created by [`Spirit::pending`](../struct.Spirit.html#method.pending) and is registered
+/// until dropped. This doesn't make the application wait for anything, it only gives a name to
+/// what is still running if the shutdown doesn't finish in time.
+pub struct Pending {
+    id: usize,
+    running: Arc<Mutex<Running>>,
+}
+
+impl Pending {
+    pub(crate) fn new(running: &Arc<Mutex<Running>>, name: String) -> Self {
+        let mut locked = running.lock();
+        let id = locked.next;
+        locked.next += 1;
+        locked.names.insert(id, name);
+        Pending {
+            id,
+            running: Arc::clone(running),
+        }
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.running.lock().names.remove(&self.id);
+    }
+}
+
+/// Starts a thread that exits the process after the timeout.
+pub(crate) fn deadline(timeout: Duration, running: &Arc<Mutex<Running>>) {
+    debug!("Shutting down, the deadline is in {:?}", timeout);
+    let running = Arc::clone(running);
+    let result = thread::Builder::new()
+        .name("spirit-shutdown".to_owned())
+        .spawn(move || {
+            thread::sleep(timeout);
+            error!(
+                "Shutdown didn't finish in {:?}, exiting; still running: {}",
+                timeout,
+                running.lock().names()
+            );
+            process::exit(1);
+        });
+    if let Err(e) = result {
+        warn!("Failed to start the shutdown deadline: {}", e);
+    }
+}
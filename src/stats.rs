@@ -0,0 +1,101 @@
+//! Statistics about spirit itself.
+//!
+//! Spirit keeps track of how the configuration reloads went and how many log messages of each
+//! level went through the loggers it installed. A snapshot can be taken with
+//! [`Spirit::stats`](../struct.Spirit.html#method.stats), to be exported into some kind of
+//! monitoring, for example.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use log::{Level, Log, Metadata, Record};
+
+// Indexed by the level (Error is 1), the first one is unused.
+static LOG_MESSAGES: [AtomicUsize; 6] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+/// Counts the messages passing through into the inner logger.
+pub(crate) struct Counting(pub(crate) Box<Log>);
+
+impl Log for Counting {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+    fn log(&self, record: &Record) {
+        if self.0.enabled(record.metadata()) {
+            LOG_MESSAGES[record.level() as usize].fetch_add(1, Ordering::Relaxed);
+            self.0.log(record);
+        }
+    }
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+/// The reload part of the statistics, updated by spirit as it goes.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Reloads {
+    pub(crate) reloads: u64,
+    pub(crate) reload_failures: u64,
+    pub(crate) last_reload: Option<SystemTime>,
+    pub(crate) last_reload_failure: Option<SystemTime>,
+    pub(crate) validation_errors: u64,
+    pub(crate) validation_warnings: u64,
+}
+
+/// A snapshot of the statistics.
+///
+/// The reload counts include the initial loading of the configuration on startup.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// Number of successful configuration (re)loads.
+    pub reloads: u64,
+    /// Number of configuration reloads that failed (and kept the old configuration).
+    pub reload_failures: u64,
+    /// When the configuration was successfully (re)loaded the last time.
+    pub last_reload: Option<SystemTime>,
+    /// When a configuration reload failed the last time.
+    pub last_reload_failure: Option<SystemTime>,
+    /// Number of validation results of the error level, across all the reloads.
+    pub validation_errors: u64,
+    /// Number of validation results of the warning level, across all the reloads.
+    pub validation_warnings: u64,
+    /// Number of log messages of each level, from the most severe one (`Error`) to `Trace`.
+    ///
+    /// Only the messages allowed by the configured logging are counted. The counts are global for
+    /// the whole process.
+    pub log_messages: Vec<(Level, u64)>,
+}
+
+impl Stats {
+    pub(crate) fn new(reloads: &Reloads) -> Self {
+        let log_messages = [
+            Level::Error,
+            Level::Warn,
+            Level::Info,
+            Level::Debug,
+            Level::Trace,
+        ]
+        .iter()
+        .map(|&level| {
+            let count = LOG_MESSAGES[level as usize].load(Ordering::Relaxed);
+            (level, count as u64)
+        })
+        .collect();
+        Self {
+            reloads: reloads.reloads,
+            reload_failures: reloads.reload_failures,
+            last_reload: reloads.last_reload,
+            last_reload_failure: reloads.last_reload_failure,
+            validation_errors: reloads.validation_errors,
+            validation_warnings: reloads.validation_warnings,
+            log_messages,
+        }
+    }
+}
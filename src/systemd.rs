@@ -0,0 +1,331 @@
+//! Integration with systemd.
+//!
+//! Services of `Type=notify` tell systemd about their state by sending datagrams to the socket
+//! passed in the `NOTIFY_SOCKET` environment variable. The [`Notify`](struct.Notify.html) helper
+//! does so on the important events of the application's life and [`notify`](fn.notify.html) can
+//! be used to send custom messages. The [`Watchdog`](struct.Watchdog.html) helper takes care of
+//! the service watchdog.
+//!
+//! See [`sd_notify`](https://www.freedesktop.org/software/systemd/man/sd_notify.html) for the
+//! details of the protocol.
+
+use std::borrow::Borrow;
+use std::env;
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use failure::Error;
+use serde::Deserialize;
+use structopt::StructOpt;
+
+use super::helpers::Helper;
+use super::{Builder, Reload, Spirit};
+
+/// An error returned when the `NOTIFY_SOCKET` is in the abstract namespace.
+///
+/// Only sockets with a path in the file system are supported (which is what systemd uses).
+#[derive(Debug, Fail)]
+#[fail(display = "Abstract notification socket {:?} is not supported", _0)]
+pub struct AbstractSocket(OsString);
+
+/// Sends a state notification to systemd.
+///
+/// The state consists of newline separated `KEY=value` assignments, like `READY=1` or
+/// `STATUS=Processing requests`.
+///
+/// Returns `false` if the application isn't supposed to notify (the `NOTIFY_SOCKET` environment
+/// variable is not set, usually because it doesn't run under systemd).
+///
+/// # Examples
+///
+/// ```rust
+/// use std::env;
+/// use std::fs;
+/// use std::os::unix::net::UnixDatagram;
+///
+/// use spirit::systemd;
+///
+/// let path = env::temp_dir().join("spirit-notify-doc");
+/// # let _ = fs::remove_file(&path);
+/// let socket = UnixDatagram::bind(&path).unwrap();
+/// env::set_var("NOTIFY_SOCKET", &path);
+///
+/// assert!(systemd::notify("STATUS=Doing something").unwrap());
+/// let mut buffer = [0; 64];
+/// let len = socket.recv(&mut buffer).unwrap();
+/// assert_eq!(b"STATUS=Doing something", &buffer[..len]);
+///
+/// env::remove_var("NOTIFY_SOCKET");
+/// assert!(!systemd::notify("READY=1").unwrap());
+/// # fs::remove_file(&path).unwrap();
+/// ```
+pub fn notify(state: &str) -> Result<bool, Error> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(false),
+    };
+    // A leading @ means the socket is in the abstract namespace.
+    if path.as_bytes().first() == Some(&b'@') {
+        bail!(AbstractSocket(path));
+    }
+    trace!("Notifying systemd at {:?}: {}", path, state);
+    UnixDatagram::unbound()?.send_to(state.as_bytes(), &path)?;
+    Ok(true)
+}
+
+fn notify_logged(state: &str) {
+    if let Err(e) = notify(state) {
+        warn!("Failed to notify systemd about {:?}: {}", state, e);
+    }
+}
+
+/// The status after a configuration (re)load.
+///
+/// Each line of the state is a separate assignment, so an error spanning multiple lines is joined
+/// into one.
+fn load_status(result: Result<(), &Error>) -> String {
+    match result {
+        Ok(()) => "STATUS=Configuration loaded".to_owned(),
+        Err(e) => {
+            let error = e.to_string().replace('\n', " ");
+            format!("STATUS=Configuration not loaded: {}", error)
+        }
+    }
+}
+
+/// A helper notifying systemd about the state of the application.
+///
+/// It sends:
+///
+/// * `READY=1` once the first configuration is loaded and the
+///   [before-bodies](../struct.Builder.html#method.before_body) registered *before* this helper
+///   finish. Therefore, apply it after the helpers that need to set things up before the service
+///   is ready.
+/// * `RELOADING=1` before each configuration reload and `READY=1` after it.
+/// * `STOPPING=1` when the application is
+///   [terminating](../struct.Spirit.html#method.terminate).
+/// * `STATUS=` with the result of the last configuration (re)load.
+///
+/// Nothing is sent if the application doesn't run under systemd. Note that going to background
+/// with `--daemonize` doesn't go well with `Type=notify`, as the notifications come from a
+/// different process than the one systemd started.
+///
+/// # Examples
+///
+/// ```rust
+/// use spirit::{Empty, Spirit};
+/// use spirit::systemd::Notify;
+///
+/// Spirit::<_, Empty, _>::new(Empty {})
+///     .with(Notify)
+///     .run(|_spirit| {
+///         // The service is ready here
+///         Ok(())
+///     });
+/// ```
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Notify;
+
+impl<S, O, C> Helper<S, O, C> for Notify
+where
+    S: Borrow<ArcSwap<C>> + Sync + Send + 'static,
+    for<'de> C: Deserialize<'de> + Send + Sync + 'static,
+    O: Debug + StructOpt + Sync + Send + 'static,
+{
+    fn apply(self, builder: Builder<S, O, C>) -> Builder<S, O, C> {
+        // Reloads before we are ready are part of the startup as far as systemd is concerned.
+        let ready = Arc::new(AtomicBool::new(false));
+        let ready_reload = Arc::clone(&ready);
+        builder
+            .on_reload(move |reload| {
+                let ready = ready_reload.load(Ordering::Relaxed);
+                match reload {
+                    Reload::Started if ready => notify_logged("RELOADING=1"),
+                    Reload::Started => (),
+                    Reload::Finished(result) => {
+                        let status = load_status(result);
+                        if ready {
+                            notify_logged(&format!("READY=1\n{}", status));
+                        } else {
+                            notify_logged(&status);
+                        }
+                    }
+                }
+            }).on_terminate(|| notify_logged("STOPPING=1"))
+            .before_body(move |_| {
+                ready.store(true, Ordering::Relaxed);
+                notify_logged("READY=1");
+                Ok(())
+            })
+    }
+}
+
+/// The watchdog timeout systemd asks for, if it is meant for us.
+fn watchdog_timeout() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(process::id()) {
+            debug!("The watchdog is meant for process {}, not us", pid);
+            return None;
+        }
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?;
+    match usec.parse() {
+        Ok(usec) => Some(Duration::from_micros(usec)),
+        Err(e) => {
+            warn!("Invalid WATCHDOG_USEC {}: {}", usec, e);
+            None
+        }
+    }
+}
+
+/// A helper keeping the systemd watchdog happy while the application is healthy.
+///
+/// If systemd asks for it (by `WatchdogSec=` in the unit file), this sends `WATCHDOG=1` every
+/// quarter of the watchdog timeout, so a ping or two may be skipped or late without systemd
+/// noticing. The ping is skipped (and systemd eventually restarts the service) if:
+///
+/// * The spirit service thread, which handles signals and reloads, panicked since the last ping
+///   (it gets restarted, but panicking over and over means something is seriously wrong).
+/// * The service thread is stuck, handling a single signal (for example a reload, with all its
+///   callbacks) for more than half of the timeout.
+/// * The health check provided by the application returns `false`.
+///
+/// The pinging starts in a [before-body](../struct.Builder.html#method.before_body) and stops
+/// once the application terminates.
+///
+/// # Examples
+///
+/// ```rust
+/// use spirit::{Empty, Spirit};
+/// use spirit::systemd::{Notify, Watchdog};
+///
+/// Spirit::<_, Empty, _>::new(Empty {})
+///     .with(Notify)
+///     .with(Watchdog::new().health_check(|| {
+///         // Check the application still makes progress
+///         true
+///     }))
+///     .run(|_spirit| {
+///         Ok(())
+///     });
+/// ```
+pub struct Watchdog {
+    health_check: Box<FnMut() -> bool + Send>,
+}
+
+impl Watchdog {
+    /// Creates the helper, checking only the spirit service thread.
+    pub fn new() -> Self {
+        Watchdog {
+            health_check: Box::new(|| true),
+        }
+    }
+
+    /// Sets (replaces) the application's health check.
+    ///
+    /// It is called from a separate thread before each ping. It should return reasonably fast ‒
+    /// no pings are sent while it blocks.
+    pub fn health_check<F: FnMut() -> bool + Send + 'static>(self, check: F) -> Self {
+        Watchdog {
+            health_check: Box::new(check),
+        }
+    }
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn keepalive<S, O, C>(
+    spirit: &Spirit<S, O, C>,
+    interval: Duration,
+    mut check: Box<FnMut() -> bool + Send>,
+) where
+    S: Borrow<ArcSwap<C>> + Send + Sync + 'static,
+    for<'de> C: Deserialize<'de> + Send + Sync,
+    O: StructOpt,
+{
+    let mut panics = spirit.service_panics.load(Ordering::Relaxed);
+    while !spirit.is_terminated() {
+        let current = spirit.service_panics.load(Ordering::Relaxed);
+        // The service thread waits for signals most of the time, it's stuck only if it takes too
+        // long to handle one.
+        let busy = spirit
+            .service_busy
+            .lock()
+            .map(|since| since.elapsed())
+            .unwrap_or_default();
+        let service_ok = if current != panics {
+            warn!("The spirit service thread panicked, skipping watchdog ping");
+            false
+        } else if busy > interval * 2 {
+            warn!(
+                "The spirit service thread seems stuck for {:?}, skipping watchdog ping",
+                busy
+            );
+            false
+        } else {
+            true
+        };
+        panics = current;
+        if service_ok {
+            if check() {
+                notify_logged("WATCHDOG=1");
+            } else {
+                warn!("The health check failed, skipping watchdog ping");
+            }
+        }
+        thread::sleep(interval);
+    }
+    debug!("Terminating the watchdog");
+}
+
+impl<S, O, C> Helper<S, O, C> for Watchdog
+where
+    S: Borrow<ArcSwap<C>> + Sync + Send + 'static,
+    for<'de> C: Deserialize<'de> + Send + Sync + 'static,
+    O: Debug + StructOpt + Sync + Send + 'static,
+{
+    fn apply(self, builder: Builder<S, O, C>) -> Builder<S, O, C> {
+        let mut check = Some(self.health_check);
+        builder.before_body(move |spirit| {
+            let interval = match watchdog_timeout() {
+                Some(timeout) => timeout / 4,
+                None => {
+                    debug!("Watchdog not enabled");
+                    return Ok(());
+                }
+            };
+            let check = check.take().expect("Before body called twice");
+            debug!("Pinging the watchdog every {:?}", interval);
+            let spirit = Arc::clone(spirit);
+            thread::Builder::new()
+                .name("spirit-watchdog".to_owned())
+                .spawn(move || keepalive(&spirit, interval, check))?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multiline_status() {
+        assert_eq!("STATUS=Configuration loaded", load_status(Ok(())));
+        let error = format_err!("Invalid config\nat line 3");
+        let expected = "STATUS=Configuration not loaded: Invalid config at line 3";
+        assert_eq!(expected, load_status(Err(&error)));
+    }
+}
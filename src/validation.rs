@@ -1,9 +1,14 @@
 //! Helpers for configuration validation.
 //!
 //! See [`config_validator`](../struct.Builder.html#method.config_validator).
-use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::result::Result as StdResult;
 use std::slice::Iter;
 
+use serde::de::{Deserialize, Deserializer, IgnoredAny};
+
 /// An error caused by failed validation
 // TODO: Better content
 #[derive(Debug, Fail)]
@@ -48,6 +53,18 @@ impl Default for Level {
     }
 }
 
+impl Display for Level {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let name = match *self {
+            Level::Nothing => "nothing",
+            Level::Hint => "hint",
+            Level::Warning => "warning",
+            Level::Error => "error",
+        };
+        fmt.write_str(name)
+    }
+}
+
 /// A validation result.
 ///
 /// The validator (see [`config_validator`](../struct.Builder.html#method.config_validator)) is
@@ -57,6 +74,7 @@ impl Default for Level {
 pub struct Result {
     level: Level,
     description: String,
+    pub(crate) key: Option<String>,
     pub(crate) on_abort: Option<Box<FnMut()>>,
     pub(crate) on_success: Option<Box<FnMut()>>,
 }
@@ -105,6 +123,22 @@ impl Result {
         &self.description
     }
 
+    /// Attaches (replaces) the configuration key the result is about.
+    ///
+    /// The key is a path to the value, like `listen.0.port`. Spirit adds to the message where the
+    /// value was set (a configuration file, for example).
+    pub fn key<K: Into<String>>(self, key: K) -> Self {
+        Self {
+            key: Some(key.into()),
+            ..self
+        }
+    }
+
+    /// Notes where the value this result is about came from.
+    pub(crate) fn set_in<S: Display>(&mut self, source: S) {
+        self.description = format!("{} (set in {})", self.description, source);
+    }
+
     /// Attaches (replaces) the success action.
     pub fn on_success<F: FnOnce() + 'static>(self, f: F) -> Self {
         let mut f = Some(f);
@@ -120,7 +154,7 @@ impl Result {
         let mut f = Some(f);
         let wrapper = move || (f.take().unwrap())();
         Self {
-            on_success: Some(Box::new(wrapper)),
+            on_abort: Some(Box::new(wrapper)),
             ..self
         }
     }
@@ -131,6 +165,7 @@ impl Debug for Result {
         fmt.debug_struct("ValidationResult")
             .field("level", &self.level)
             .field("description", &self.description)
+            .field("key", &self.key)
             .field(
                 "on_abort",
                 if self.on_abort.is_some() {
@@ -217,6 +252,15 @@ impl Results {
     pub fn max_level(&self) -> Option<Level> {
         self.iter().map(|r| r.level).max()
     }
+
+    /// Runs the abort actions of all the results.
+    pub(crate) fn abort(&mut self) {
+        for r in &mut self.0 {
+            if let Some(abort) = r.on_abort.as_mut() {
+                abort();
+            }
+        }
+    }
 }
 
 impl<'a> IntoIterator for &'a Results {
@@ -242,3 +286,124 @@ where
         Results(vals.into_iter().map(Into::into).collect())
     }
 }
+
+thread_local! {
+    static UNUSED: RefCell<Option<Vec<Vec<String>>>> = Default::default();
+    static CHECK_ONLY: Cell<bool> = Default::default();
+}
+
+/// Checks if the configuration being validated is only checked, never to be used.
+///
+/// This is the case with the `--check-config` command line option. As the application may be
+/// already running with the resources a validator would prepare (like listening sockets), the
+/// validator should check only what it can without creating them.
+///
+/// This makes sense only when called from within a validator.
+pub fn check_only() -> bool {
+    CHECK_ONLY.with(Cell::get)
+}
+
+/// Clears the [`check_only`](fn.check_only.html) flag when dropped, even during a panic.
+struct CheckingGuard;
+
+impl Drop for CheckingGuard {
+    fn drop(&mut self) {
+        CHECK_ONLY.with(|check| check.set(false));
+    }
+}
+
+/// Runs the closure with [`check_only`](fn.check_only.html) returning `true`.
+pub(crate) fn checking<R, F: FnOnce() -> R>(f: F) -> R {
+    CHECK_ONLY.with(|check| check.set(true));
+    let _guard = CheckingGuard;
+    f()
+}
+
+/// Runs the closure, collecting the keys reported by [`Unused`](struct.Unused.html).
+///
+/// The keys come in groups, one for each place an `Unused` was deserialized in. They are only the
+/// names, without the path to them.
+pub(crate) fn collect_unused<R, F: FnOnce() -> R>(f: F) -> (R, Vec<Vec<String>>) {
+    UNUSED.with(|unused| *unused.borrow_mut() = Some(Vec::new()));
+    let result = f();
+    let unused = UNUSED.with(|unused| unused.borrow_mut().take().unwrap_or_default());
+    (result, unused)
+}
+
+/// A catch-all for configuration keys nothing else used.
+///
+/// Spirit reports configuration keys that nothing deserialized, which are usually typos (see
+/// [`config_unused_keys`](../struct.Builder.html#method.config_unused_keys)). However, it can't
+/// look into structures with `#[serde(flatten)]` fields, because serde quietly throws away the
+/// keys none of the flattened fields wanted. Placing this as the last flattened field of such
+/// structure hands these keys over to spirit.
+///
+/// This works only if the other flattened fields are structures. Maps and internally tagged enums
+/// don't mark the keys they have taken, so these would be reported too.
+///
+/// # Examples
+///
+/// ```rust
+/// # #![allow(dead_code)]
+/// extern crate serde;
+/// #[macro_use]
+/// extern crate serde_derive;
+/// extern crate spirit;
+///
+/// use spirit::{Empty, Spirit};
+/// use spirit::validation::Unused;
+///
+/// #[derive(Default, Deserialize)]
+/// struct Common {
+///     #[serde(default)]
+///     name: String,
+/// }
+///
+/// #[derive(Default, Deserialize)]
+/// struct Service {
+///     #[serde(flatten)]
+///     common: Common,
+///     #[serde(default)]
+///     port: u16,
+///     #[serde(flatten)]
+///     unused: Unused,
+/// }
+///
+/// fn main() {
+///     Spirit::<_, Empty, _>::new(Service::default())
+///         .run(|_spirit| {
+///             Ok(())
+///         });
+/// }
+/// ```
+#[derive(Copy, Clone, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Unused;
+
+impl<'de> Deserialize<'de> for Unused {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> StdResult<Self, D::Error> {
+        let keys = BTreeMap::<String, IgnoredAny>::deserialize(d)?;
+        UNUSED.with(|unused| {
+            if let Some(ref mut unused) = *unused.borrow_mut() {
+                unused.push(keys.keys().cloned().collect());
+            }
+        });
+        Ok(Unused)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic;
+
+    use super::*;
+
+    #[test]
+    fn checking_panic() {
+        assert!(!check_only());
+        let result = panic::catch_unwind(|| checking(|| panic!("Validator failed")));
+        assert!(result.is_err());
+        assert!(!check_only());
+        assert!(checking(check_only));
+        assert!(!check_only());
+    }
+}
@@ -0,0 +1,201 @@
+//! Automatic reloading of the configuration when the files change.
+//!
+//! See [`config_watch`](../struct.Builder.html#method.config_watch).
+
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use failure::Error;
+use notify::{self, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Deserialize;
+use structopt::StructOpt;
+
+use super::{log_errors, Spirit};
+
+/// How long the files need to be quiet before we reload.
+///
+/// Editors and tools like to do several operations when saving a file, this makes them into one
+/// reload.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// How often the watcher thread checks if it should terminate.
+const TERMINATE_CHECK: Duration = Duration::from_secs(1);
+
+/// The absolute path, without resolving the last component if it is a symlink.
+fn absolute(path: &Path) -> Option<PathBuf> {
+    let parent = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    match path.file_name() {
+        Some(name) => parent.canonicalize().ok().map(|parent| parent.join(name)),
+        None => path.canonicalize().ok(),
+    }
+}
+
+/// What the configuration files resolve to.
+///
+/// Configuration files are often symlinks (for example in containers, where a whole directory of
+/// them is swapped by changing one symlink). Such swap produces an event on some unrelated path,
+/// so we detect it by checking the targets changed.
+fn resolve(files: &[PathBuf]) -> Vec<Option<PathBuf>> {
+    files.iter().map(|file| file.canonicalize().ok()).collect()
+}
+
+fn watched_dirs(files: &[PathBuf]) -> HashSet<PathBuf> {
+    let mut dirs = HashSet::new();
+    for file in files {
+        for path in absolute(file).into_iter().chain(file.canonicalize().ok()) {
+            if path.is_dir() {
+                dirs.insert(path);
+            } else if let Some(parent) = path.parent() {
+                // We watch the parent directory, not the file itself. That way we notice if the
+                // file is replaced, not only modified in place.
+                dirs.insert(parent.to_owned());
+            }
+        }
+    }
+    dirs
+}
+
+struct Watch<S, O, C>
+where
+    S: Borrow<ArcSwap<C>> + 'static,
+{
+    spirit: Arc<Spirit<S, O, C>>,
+    files: Vec<PathBuf>,
+    absolute: Vec<Option<PathBuf>>,
+    resolved: Vec<Option<PathBuf>>,
+    watcher: RecommendedWatcher,
+    watched: HashSet<PathBuf>,
+}
+
+impl<S, O, C> Watch<S, O, C>
+where
+    S: Borrow<ArcSwap<C>> + Send + Sync + 'static,
+    for<'de> C: Deserialize<'de> + Send + Sync,
+    O: StructOpt,
+{
+    fn relevant(&mut self, event: &DebouncedEvent) -> bool {
+        match *event {
+            // These are sent right away, we wait for the debounced version.
+            DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) => false,
+            // We don't know what happened, so better reload.
+            DebouncedEvent::Rescan => true,
+            DebouncedEvent::Error(ref e, ref path) => {
+                warn!("Error watching configuration at {:?}: {}", path, e);
+                true
+            }
+            DebouncedEvent::Rename(ref old, ref new) => {
+                self.matches(old) || self.matches(new) || self.swapped()
+            }
+            DebouncedEvent::Create(ref path)
+            | DebouncedEvent::Write(ref path)
+            | DebouncedEvent::Chmod(ref path)
+            | DebouncedEvent::Remove(ref path) => self.matches(path) || self.swapped(),
+        }
+    }
+
+    /// Checks if a symlink somewhere up the path was swapped to point to another file.
+    fn swapped(&mut self) -> bool {
+        let resolved = resolve(&self.files);
+        let changed = resolved != self.resolved;
+        self.resolved = resolved;
+        self.absolute = self.files.iter().map(|file| absolute(file)).collect();
+        changed
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        let is_path =
+            |candidate: &Option<PathBuf>| candidate.as_ref().map(|c| c == path).unwrap_or(false);
+        if self.absolute.iter().any(&is_path) || self.resolved.iter().any(&is_path) {
+            return true;
+        }
+        let in_dir = self
+            .resolved
+            .iter()
+            .filter_map(|dir| dir.as_ref())
+            .any(|dir| dir.is_dir() && path.parent() == Some(dir));
+        // Only the files that would get loaded from the directory are interesting.
+        in_dir && (self.spirit.hooks.lock().config_filter)(path)
+    }
+
+    /// Watches the directories the configuration lives in now.
+    ///
+    /// After a symlink swap, the files may live in a completely different place. The directories
+    /// no longer needed are not watched any more.
+    fn rewatch(&mut self) -> Result<(), Error> {
+        let dirs = watched_dirs(&self.files);
+        for dir in self.watched.difference(&dirs) {
+            debug!("No longer watching {:?} for configuration changes", dir);
+            // If the directory is gone, so is the watch, and this fails
+            if let Err(e) = self.watcher.unwatch(dir) {
+                debug!("Failed to unwatch {:?}: {}", dir, e);
+            }
+        }
+        self.watched.retain(|dir| dirs.contains(dir));
+        for dir in dirs {
+            if !self.watched.contains(&dir) {
+                debug!("Watching {:?} for configuration changes", dir);
+                self.watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+                self.watched.insert(dir);
+            }
+        }
+        Ok(())
+    }
+
+    fn run(mut self, events: &Receiver<DebouncedEvent>) {
+        while !self.spirit.is_terminated() {
+            let event = match events.recv_timeout(TERMINATE_CHECK) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => {
+                    warn!("Configuration watcher terminated");
+                    return;
+                }
+            };
+            trace!("Configuration file event {:?}", event);
+            let mut reload = self.relevant(&event);
+            // Several events may come in one go, reload only once for all of them.
+            while let Ok(event) = events.try_recv() {
+                trace!("Configuration file event {:?}", event);
+                reload = self.relevant(&event) || reload;
+            }
+            if reload && !self.spirit.is_terminated() {
+                info!("Configuration files changed, reloading");
+                let _ = log_errors(|| self.spirit.config_reload());
+                let _ = log_errors(|| self.rewatch());
+            }
+        }
+        debug!("Terminating the configuration watcher");
+    }
+}
+
+pub(crate) fn start<S, O, C>(spirit: &Arc<Spirit<S, O, C>>) -> Result<(), Error>
+where
+    S: Borrow<ArcSwap<C>> + Send + Sync + 'static,
+    for<'de> C: Deserialize<'de> + Send + Sync + 'static,
+    O: StructOpt + Send + Sync + 'static,
+{
+    let files = spirit.config_files.clone();
+    let (sender, receiver) = mpsc::channel();
+    let mut watch = Watch {
+        spirit: Arc::clone(spirit),
+        absolute: files.iter().map(|file| absolute(file)).collect(),
+        resolved: resolve(&files),
+        files,
+        watcher: notify::watcher(sender, DEBOUNCE)?,
+        watched: HashSet::new(),
+    };
+    watch.rewatch()?;
+    thread::Builder::new()
+        .name("spirit-watch".to_owned())
+        .spawn(move || watch.run(&receiver))?;
+    Ok(())
+}
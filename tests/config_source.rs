@@ -0,0 +1,118 @@
+//! Telling where each configuration value came from.
+//!
+//! Spirit takes its command line from the process and sets up process-wide things (the logger,
+//! signal handlers), so the application runs in a child process started from this one.
+
+#[macro_use]
+extern crate serde_derive;
+extern crate spirit;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{self, Command, Output};
+use std::sync::Arc;
+
+use spirit::validation::{Result as ValidationResult, Results as ValidationResults};
+use spirit::{ConfigSource, Empty, Spirit};
+
+const CHILD: &str = "SPIRIT_TEST_CHILD";
+
+const DEFAULTS: &str = r#"
+name = "default"
+port = 1
+host = "localhost"
+workers = 1
+debug = false
+"#;
+
+#[allow(dead_code)]
+#[derive(Default, Deserialize)]
+struct Config {
+    name: String,
+    port: u16,
+    host: String,
+    workers: u32,
+    debug: bool,
+}
+
+/// Warns about every key, to see where the messages say they were set.
+fn validator(_: &Arc<Config>, _: &mut Config, _: &Empty) -> ValidationResults {
+    let mut results = ValidationResults::new();
+    for key in &["name", "port", "host", "workers", "debug"] {
+        results.merge(ValidationResult::warning(format!("Checked {}", key)).key(*key));
+    }
+    results
+}
+
+fn child() {
+    let dir = PathBuf::from(env::var(CHILD).unwrap());
+    Spirit::<_, Empty, _>::new(Config::default())
+        .config_defaults(DEFAULTS)
+        .config_env("SPIRIT_SOURCE")
+        .config_validator(validator)
+        .run(move |spirit| {
+            spirit.terminate();
+            let source = |key| spirit.config_source(key);
+            assert_eq!(Some(ConfigSource::Defaults), source("debug"));
+            assert_eq!(Some(ConfigSource::File(dir.join("a.toml"))), source("port"));
+            // The later file wins
+            assert_eq!(Some(ConfigSource::File(dir.join("b.toml"))), source("host"));
+            let env = ConfigSource::Env("SPIRIT_SOURCE".to_owned());
+            assert_eq!(Some(env), source("workers"));
+            assert_eq!(Some(ConfigSource::Override), source("name"));
+            assert_eq!(None, source("nonexistent"));
+            Ok(())
+        });
+}
+
+fn run(dir: &PathBuf, check: bool) -> Output {
+    let mut command = Command::new(env::current_exe().unwrap());
+    command
+        .env(CHILD, dir)
+        .env("SPIRIT_SOURCE_WORKERS", "4")
+        .arg("--config-override")
+        .arg("name=overridden");
+    if check {
+        command.arg("--check-config");
+    }
+    command
+        .arg(dir.join("a.toml"))
+        .arg(dir.join("b.toml"))
+        .output()
+        .unwrap()
+}
+
+fn main() {
+    if env::var(CHILD).is_ok() {
+        return child();
+    }
+    let dir: PathBuf = env::temp_dir().join(format!("spirit-source-{}", process::id()));
+    fs::create_dir(&dir).unwrap();
+    fs::write(dir.join("a.toml"), "port = 2\nhost = \"a\"\n").unwrap();
+    fs::write(dir.join("b.toml"), "host = \"b\"\n").unwrap();
+
+    let output = run(&dir, false);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    println!("Config sources OK");
+
+    let output = run(&dir, true);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let expected = [
+        "Checked name (set in command line override)".to_owned(),
+        format!("Checked port (set in {})", dir.join("a.toml").display()),
+        format!("Checked host (set in {})", dir.join("b.toml").display()),
+        "Checked workers (set in SPIRIT_SOURCE_* environment variables)".to_owned(),
+        "Checked debug (set in config defaults)".to_owned(),
+    ];
+    for message in &expected {
+        assert!(stdout.contains(message), "{} not in {}", message, stdout);
+    }
+    println!("Validation messages OK");
+    fs::remove_dir_all(&dir).unwrap();
+}
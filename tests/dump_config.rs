@@ -0,0 +1,66 @@
+//! Dumping the configuration with `--dump-config`.
+//!
+//! Spirit takes its command line from the process and sets up process-wide things (the logger,
+//! signal handlers), so the application runs in a child process started from this one.
+
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate spirit;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{self, Command, Output};
+
+use serde_json::Value;
+use spirit::{Empty, Spirit};
+
+const CHILD: &str = "SPIRIT_TEST_CHILD";
+
+#[derive(Default, Deserialize)]
+struct Config {
+    answer: u32,
+}
+
+fn child() {
+    Spirit::<_, Empty, _>::new(Config::default()).run(|spirit| {
+        panic!("The application runs with {}", spirit.config().answer);
+    });
+}
+
+fn dump(config: &str) -> Output {
+    let path: PathBuf = env::temp_dir().join(format!("spirit-dump-{}.toml", process::id()));
+    fs::write(&path, config).unwrap();
+    let output = Command::new(env::current_exe().unwrap())
+        .env(CHILD, "1")
+        .arg("--dump-config")
+        .arg("json")
+        .arg(&path)
+        .output()
+        .unwrap();
+    fs::remove_file(&path).unwrap();
+    output
+}
+
+fn main() {
+    if env::var(CHILD).is_ok() {
+        return child();
+    }
+    let output = dump("answer = 42\n[[logging]]\ntype = \"stderr\"\nlevel = \"DEBUG\"\n");
+    assert!(output.status.success());
+    let dumped: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(42, dumped["answer"]);
+    // The defaults of our sections are filled in
+    assert!(dumped["daemon"].is_object());
+    let logging = &dumped["logging"][0];
+    assert_eq!("stderr", logging["type"]);
+    assert_eq!("DEBUG", logging["level"]);
+    assert_eq!("auto", logging["color"]);
+    assert_eq!("text", logging["format"]);
+    assert_eq!("local", logging["timezone"]);
+    println!("Dump config OK");
+    // The application's part is checked even though it's not serialized
+    assert!(!dump("answer = \"many\"\n").status.success());
+    println!("Invalid config OK");
+}
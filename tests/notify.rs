@@ -0,0 +1,139 @@
+//! The notifications the `Notify` and `Watchdog` helpers send during the life of the application.
+//!
+//! Spirit takes its command line from the process and sets up process-wide things (the logger,
+//! signal handlers), so the application runs in a child process started from this one.
+
+extern crate libc;
+extern crate nix;
+extern crate spirit;
+
+use std::cmp;
+use std::env;
+use std::fs;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::process::{self, Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use spirit::systemd::{Notify, Watchdog};
+use spirit::{Empty, Spirit};
+
+const CHILD: &str = "SPIRIT_TEST_CHILD";
+
+fn child(mode: &str) {
+    let builder = Spirit::<_, Empty, _>::new(Empty {});
+    let builder = match mode {
+        "notify" => builder.with(Notify),
+        // The signal makes the service thread stuck for a while
+        "watchdog" => builder
+            .with(Watchdog::new())
+            .on_signal(libc::SIGUSR2, || thread::sleep(Duration::from_millis(1500))),
+        _ => unreachable!(),
+    };
+    builder.run(|spirit| {
+        while !spirit.is_terminated() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    });
+}
+
+/// Makes sure the child doesn't stay around if the test fails.
+struct Guard(Child);
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+struct Test {
+    dir: PathBuf,
+    socket: UnixDatagram,
+    child: Guard,
+}
+
+impl Test {
+    fn start(mode: &str, watchdog_usec: Option<&str>) -> Self {
+        let dir = env::temp_dir().join(format!("spirit-{}-test-{}", mode, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut command = Command::new(env::current_exe().unwrap());
+        command
+            .env(CHILD, mode)
+            .env("NOTIFY_SOCKET", &path)
+            .env_remove("WATCHDOG_PID")
+            .env_remove("WATCHDOG_USEC");
+        if let Some(usec) = watchdog_usec {
+            command.env("WATCHDOG_USEC", usec);
+        }
+        let child = Guard(command.spawn().unwrap());
+        Test { dir, socket, child }
+    }
+
+    fn recv(&self) -> String {
+        let mut buffer = [0; 1024];
+        let len = self.socket.recv(&mut buffer).expect("Missing notification");
+        String::from_utf8(buffer[..len].to_vec()).unwrap()
+    }
+
+    fn signal(&self, signal: Signal) {
+        signal::kill(Pid::from_raw(self.child.0.id() as i32), signal).unwrap();
+    }
+
+    fn finish(mut self) {
+        assert!(self.child.0.wait().unwrap().success());
+        fs::remove_dir_all(&self.dir).unwrap();
+    }
+}
+
+fn notify() {
+    let test = Test::start("notify", None);
+    assert_eq!("STATUS=Configuration loaded", test.recv());
+    assert_eq!("READY=1", test.recv());
+    test.signal(Signal::SIGHUP);
+    assert_eq!("RELOADING=1", test.recv());
+    assert_eq!("READY=1\nSTATUS=Configuration loaded", test.recv());
+    test.signal(Signal::SIGTERM);
+    assert_eq!("STOPPING=1", test.recv());
+    test.finish();
+}
+
+fn watchdog() {
+    // Pings every 100ms, the service thread is stuck after 200ms
+    let test = Test::start("watchdog", Some("400000"));
+    for _ in 0..3 {
+        assert_eq!("WATCHDOG=1", test.recv());
+    }
+    test.signal(Signal::SIGUSR2);
+    let start = Instant::now();
+    let mut last = start;
+    let mut longest = Duration::from_secs(0);
+    while start.elapsed() < Duration::from_secs(2) {
+        assert_eq!("WATCHDOG=1", test.recv());
+        longest = cmp::max(longest, last.elapsed());
+        last = Instant::now();
+    }
+    let gap = longest > Duration::from_millis(600);
+    assert!(gap, "No gap in pings: {:?}", longest);
+    test.signal(Signal::SIGTERM);
+    test.finish();
+}
+
+fn main() {
+    if let Ok(mode) = env::var(CHILD) {
+        return child(&mode);
+    }
+    notify();
+    println!("Notify OK");
+    watchdog();
+    println!("Watchdog OK");
+}
@@ -0,0 +1,41 @@
+//! Signals arriving after the application started terminating.
+//!
+//! Spirit takes its command line from the process and sets up process-wide things (the logger,
+//! signal handlers), so the application runs in a child process started from this one.
+
+extern crate nix;
+extern crate spirit;
+
+use std::env;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+use nix::sys::signal::{self, Signal};
+use nix::unistd;
+use spirit::{Empty, Spirit};
+
+const CHILD: &str = "SPIRIT_TEST_CHILD";
+
+fn child() {
+    Spirit::<_, Empty, _>::new(Empty {}).run(|spirit| {
+        spirit.terminate();
+        signal::kill(unistd::getpid(), Signal::SIGHUP)?;
+        // Give the signal thread time to handle it
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(1, spirit.stats().reloads, "Reloaded during shutdown");
+        Ok(())
+    });
+}
+
+fn main() {
+    if env::var(CHILD).is_ok() {
+        return child();
+    }
+    let status = Command::new(env::current_exe().unwrap())
+        .env(CHILD, "1")
+        .status()
+        .unwrap();
+    assert!(status.success());
+    println!("Reload after terminate OK");
+}
@@ -0,0 +1,70 @@
+//! Reloading the configuration when the files change.
+//!
+//! Spirit takes its command line from the process and sets up process-wide things (the logger,
+//! signal handlers), so the application runs in a child process started from this one.
+
+extern crate spirit;
+
+use std::env;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+use std::process::{self, Command};
+use std::thread;
+use std::time::Duration;
+
+use spirit::{Empty, Spirit};
+
+const CHILD: &str = "SPIRIT_TEST_CHILD";
+
+/// Longer than the debouncing of the watcher.
+const SETTLE: Duration = Duration::from_millis(1500);
+
+fn child(dir: &Path) {
+    let dir = dir.to_owned();
+    Spirit::<_, Empty, _>::new(Empty {})
+        .config_watch()
+        .run(move |spirit| {
+            let reloads = |expected| {
+                thread::sleep(SETTLE);
+                assert_eq!(expected, spirit.stats().reloads);
+            };
+            reloads(1);
+            fs::write(dir.join("config.toml"), "# Modified\n")?;
+            reloads(2);
+            // Swap the whole directory, the way kubernetes does it with config maps
+            fs::create_dir(dir.join("v2"))?;
+            fs::write(dir.join("v2/config.toml"), "# Second version\n")?;
+            symlink("v2", dir.join("data.new"))?;
+            fs::rename(dir.join("data.new"), dir.join("data"))?;
+            reloads(3);
+            // The new place is watched
+            fs::write(dir.join("v2/config.toml"), "# Modified second version\n")?;
+            reloads(4);
+            // But the old one is no longer relevant
+            fs::write(dir.join("v1/config.toml"), "# Modified first version\n")?;
+            reloads(4);
+            spirit.terminate();
+            Ok(())
+        });
+}
+
+fn main() {
+    if let Some(dir) = env::var_os(CHILD) {
+        return child(Path::new(&dir));
+    }
+    let dir = env::temp_dir().join(format!("spirit-watch-{}", process::id()));
+    fs::create_dir(&dir).unwrap();
+    fs::create_dir(dir.join("v1")).unwrap();
+    fs::write(dir.join("v1/config.toml"), "# First version\n").unwrap();
+    symlink("v1", dir.join("data")).unwrap();
+    symlink("data/config.toml", dir.join("config.toml")).unwrap();
+    let status = Command::new(env::current_exe().unwrap())
+        .env(CHILD, &dir)
+        .arg(dir.join("config.toml"))
+        .status()
+        .unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(status.success());
+    println!("Watch OK");
+}